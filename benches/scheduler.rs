@@ -0,0 +1,96 @@
+//! Compares the cost of one cron tick between a full scan of every alarm,
+//! re-parsing each cron expression as the cron thread used to, and the timer
+//! queue, with 100k alarms spread over 1000 users.
+//!
+//! Run with `cargo bench --bench scheduler`.
+
+use chrono::prelude::*;
+use hyper_bed_caller::clock::{Clock, ManualClock};
+use hyper_bed_caller::scheduler::Scheduler;
+use hyper_bed_caller::store::{Alarm, State};
+use std::cell::RefCell;
+use std::str::FromStr;
+use std::time::Instant;
+
+const USERS: i64 = 1000;
+const ALARMS_PER_USER: i64 = 100;
+const TICKS: i64 = 3600;
+
+fn build_state() -> State {
+  let state = State::new();
+  {
+    let mut alarms_map = state.alarms.borrow_mut();
+    for user_id in 1..=USERS {
+      let mut alarms = vec![];
+      for i in 0..ALARMS_PER_USER {
+        let minute = (user_id + i) % 60;
+        let hour = (user_id * 7 + i) % 24;
+        let cron = format!("0 {} {} * * *", minute, hour);
+        state.add_alarm(
+          &mut alarms,
+          Alarm::new(user_id, user_id, cron.as_str(), "", false),
+        );
+      }
+      alarms_map.insert(user_id, RefCell::new(alarms));
+    }
+  }
+  state
+}
+
+fn main() {
+  let clock = ManualClock::new(Utc.ymd(2020, 1, 1).and_hms(0, 0, 0));
+  let state = build_state();
+  let total = USERS * ALARMS_PER_USER;
+
+  let start = Instant::now();
+  let last_tick = clock.now().with_timezone(&Local);
+  let mut due = 0;
+  for alarms in state.alarms.borrow().values() {
+    for alarm in alarms.borrow().iter() {
+      let schedule = cron::Schedule::from_str(&alarm.cron).unwrap();
+      for next in schedule.after(&last_tick).take(1) {
+        if next.timestamp() <= last_tick.timestamp() + 1 {
+          due += 1;
+        }
+      }
+    }
+  }
+  println!(
+    "full scan:   {:>10.3?} per tick over {} alarms ({} due)",
+    start.elapsed(),
+    total,
+    due
+  );
+
+  let scheduler = Scheduler::new();
+  let start = Instant::now();
+  for user_id in 1..=USERS {
+    scheduler.reschedule(&state, user_id, clock.timestamp());
+  }
+  println!(
+    "queue build: {:>10.3?} for {} alarms",
+    start.elapsed(),
+    scheduler.len()
+  );
+
+  let start = Instant::now();
+  let mut fired = 0;
+  for _ in 0..TICKS {
+    clock.advance(chrono::Duration::seconds(1));
+    let now = clock.timestamp();
+    let timers = scheduler.pop_due(now);
+    fired += timers.len();
+    let mut users: Vec<i64> = timers.iter().map(|timer| timer.user_id).collect();
+    users.sort_unstable();
+    users.dedup();
+    for user_id in users {
+      scheduler.reschedule(&state, user_id, now);
+    }
+  }
+  println!(
+    "queue tick:  {:>10.3?} per tick averaged over {} ticks ({} fired)",
+    start.elapsed() / TICKS as u32,
+    TICKS,
+    fired
+  );
+}
@@ -1,8 +1,8 @@
-use crate::store::Alarm;
+use crate::clock::Clock;
+use crate::ring::RingState;
+use crate::store::{Alarm, QuietHours, Vacation};
 use chrono::{self, prelude::*};
-use cron;
 use std::fmt::Display;
-use std::str::FromStr;
 
 #[derive(Debug)]
 pub struct AlarmSchedule<'a, Z: TimeZone> {
@@ -107,13 +107,35 @@ where
   }
 }
 
-pub fn get_recent_schedule<Z>(alarms: &Vec<Alarm>, timezone: Z, chat_id: i64) -> AlarmSchedule<Z>
+/// The alarm that rings next and when, as the scheduler will ring it: not
+/// suspended by `vacation`, and moved out of or dropped in `quiet_hours`.
+pub fn get_recent_schedule<'a, Z>(
+  alarms: &'a Vec<Alarm>,
+  timezone: Z,
+  chat_id: i64,
+  vacation: Option<&Vacation>,
+  quiet_hours: Option<&QuietHours>,
+  clock: &dyn Clock,
+) -> AlarmSchedule<'a, Z>
+where
+  Z: TimeZone + 'static,
+{
+  let now = clock.now().with_timezone(&timezone);
+  get_recent_schedule_after(alarms, &now, chat_id, vacation, quiet_hours)
+}
+
+pub fn get_recent_schedule_after<'a, Z>(
+  alarms: &'a Vec<Alarm>,
+  now: &DateTime<Z>,
+  chat_id: i64,
+  vacation: Option<&Vacation>,
+  quiet_hours: Option<&QuietHours>,
+) -> AlarmSchedule<'a, Z>
 where
   Z: TimeZone + 'static,
 {
-  let next_timestamp = 0;
+  let mut next_timestamp = 0;
   let mut recent = AlarmSchedule::default();
-  let now = timezone.from_utc_datetime(&Local::now().naive_utc());
   for alarm in alarms.iter() {
     if alarm.is_disabled || alarm.is_onceoff {
       continue;
@@ -121,26 +143,35 @@ where
     if chat_id < 0 && alarm.chat_id != chat_id {
       continue;
     }
-    let next_alarm = get_next_schedule(&alarm.cron, &now);
-    let t = next_alarm.to_timestamp();
+    let t = get_occurrence_timestamp(
+      alarm,
+      now.timezone(),
+      now.timestamp(),
+      vacation,
+      quiet_hours,
+    );
     if t >= 0 && (next_timestamp == 0 || t < next_timestamp) {
-      recent = AlarmSchedule::new(next_alarm.inner.unwrap(), alarm);
+      next_timestamp = t;
+      recent = AlarmSchedule::new(now.timezone().timestamp(t, 0), alarm);
     }
   }
   return recent;
 }
 
-pub fn get_recent_schedule_mut<Z>(
-  alarms: &mut Vec<Alarm>,
+pub fn get_recent_schedule_mut<'a, Z>(
+  alarms: &'a mut Vec<Alarm>,
   timezone: Z,
   chat_id: i64,
-) -> AlarmScheduleMut<Z>
+  vacation: Option<&Vacation>,
+  quiet_hours: Option<&QuietHours>,
+  clock: &dyn Clock,
+) -> AlarmScheduleMut<'a, Z>
 where
   Z: TimeZone + 'static,
 {
-  let next_timestamp = 0;
+  let mut next_timestamp = 0;
   let mut recent = AlarmScheduleMut::default();
-  let now = timezone.from_utc_datetime(&Local::now().naive_utc());
+  let now = clock.now().with_timezone(&timezone);
   for alarm in alarms.iter_mut() {
     if alarm.is_disabled || alarm.is_onceoff {
       continue;
@@ -148,10 +179,16 @@ where
     if chat_id < 0 && alarm.chat_id != chat_id {
       continue;
     }
-    let next_alarm = get_next_schedule(&alarm.cron, &now);
-    let t = next_alarm.to_timestamp();
+    let t = get_occurrence_timestamp(
+      alarm,
+      timezone.clone(),
+      now.timestamp(),
+      vacation,
+      quiet_hours,
+    );
     if t >= 0 && (next_timestamp == 0 || t < next_timestamp) {
-      recent = AlarmScheduleMut::new(next_alarm.inner.unwrap(), alarm);
+      next_timestamp = t;
+      recent = AlarmScheduleMut::new(timezone.timestamp(t, 0), alarm);
     }
   }
   return recent;
@@ -274,14 +311,179 @@ where
 {
 }
 
-pub fn get_next_schedule<T, Z>(cron: T, after: &DateTime<Z>) -> Schedule<Z>
+/// How many quiet windows in a row may drop occurrences of an alarm before
+/// it is considered never to ring.
+const MAX_QUIET_SKIPS: usize = 400;
+
+/// Returns the timestamp at which the scheduler should next look at `alarm`,
+/// or -1 if it will never ring again. A ringing alarm is due at its retry,
+/// others at their next occurrence, see `get_occurrence_timestamp`.
+pub fn get_due_timestamp<Z>(
+  alarm: &Alarm,
+  timezone: Z,
+  after: i64,
+  vacation: Option<&Vacation>,
+  quiet_hours: Option<&QuietHours>,
+) -> i64
+where
+  Z: TimeZone + 'static,
+{
+  if let Some(due_at) = alarm.ring.due_at() {
+    return due_at;
+  }
+  get_occurrence_timestamp(alarm, timezone, after, vacation, quiet_hours)
+}
+
+/// Returns when `alarm` next rings after `after`, or -1 if it never does.
+/// Occurrences suspended by `vacation` are skipped, and occurrences of
+/// non-strict alarms inside `quiet_hours` are moved to the end of the window,
+/// or dropped if the user asked for that.
+pub fn get_occurrence_timestamp<Z>(
+  alarm: &Alarm,
+  timezone: Z,
+  after: i64,
+  vacation: Option<&Vacation>,
+  quiet_hours: Option<&QuietHours>,
+) -> i64
+where
+  Z: TimeZone + 'static,
+{
+  let after = timezone.timestamp(after, 0);
+  let mut next = get_next_schedule_with_vacation(alarm, &after, vacation);
+  let quiet_hours = match quiet_hours {
+    Some(quiet_hours) if !alarm.is_strict => quiet_hours,
+    _ => return next.to_timestamp(),
+  };
+  for _ in 0..MAX_QUIET_SKIPS {
+    let datetime = match next.inner {
+      None => return -1,
+      Some(datetime) => datetime,
+    };
+    if !quiet_hours.contains(&datetime) {
+      return datetime.timestamp();
+    }
+    let end = quiet_hours.end_after(&datetime);
+    if !quiet_hours.is_dropping {
+      return end.timestamp();
+    }
+    next = get_next_schedule_with_vacation(alarm, &(end - chrono::Duration::seconds(1)), vacation);
+  }
+  -1
+}
+
+/// Returns the occurrence of `alarm` whose heads-up is the first one after
+/// `after`, or -1 if there is none. Alarms that are ringing or following up,
+/// skipped once or already warned about that occurrence get no heads-up.
+pub fn get_heads_up_occurrence<Z>(
+  alarm: &Alarm,
+  timezone: Z,
+  after: i64,
+  vacation: Option<&Vacation>,
+  quiet_hours: Option<&QuietHours>,
+) -> i64
+where
+  Z: TimeZone + 'static,
+{
+  if alarm.heads_up <= 0 || alarm.is_disabled || alarm.is_onceoff || alarm.ring != RingState::Idle {
+    return -1;
+  }
+  let occurrence = get_occurrence_timestamp(
+    alarm,
+    timezone,
+    after + alarm.heads_up,
+    vacation,
+    quiet_hours,
+  );
+  if occurrence == alarm.heads_up_sent {
+    return -1;
+  }
+  occurrence
+}
+
+/// Returns the first occurrence of `alarm` after `after` that is not
+/// suspended by `vacation`.
+pub fn get_next_schedule_with_vacation<Z>(
+  alarm: &Alarm,
+  after: &DateTime<Z>,
+  vacation: Option<&Vacation>,
+) -> Schedule<Z>
+where
+  Z: TimeZone,
+{
+  match vacation {
+    Some(vacation) if vacation.suspends(alarm) && vacation.until > after.timestamp() => {
+      get_next_schedule(alarm, &after.timezone().timestamp(vacation.until - 1, 0))
+    }
+    _ => get_next_schedule(alarm, after),
+  }
+}
+
+/// Tells whether the first occurrence of `alarm` after `after` falls inside
+/// `quiet_hours`.
+pub fn rings_in_quiet_hours<Z>(alarm: &Alarm, quiet_hours: &QuietHours, after: &DateTime<Z>) -> bool
+where
+  Z: TimeZone,
+{
+  match get_next_schedule(alarm, after).inner {
+    None => false,
+    Some(datetime) => quiet_hours.contains(&datetime),
+  }
+}
+
+/// Returns the first occurrence of `alarm` after `after`. Broken alarms have
+/// none.
+pub fn get_next_schedule<Z>(alarm: &Alarm, after: &DateTime<Z>) -> Schedule<Z>
 where
-  T: AsRef<str>,
   Z: TimeZone,
 {
-  let schedule = cron::Schedule::from_str(cron.as_ref()).unwrap();
+  let schedule = match &alarm.schedule {
+    None => return Schedule::default(),
+    Some(schedule) => schedule,
+  };
   for datetime in schedule.after(after).take(1) {
     return Schedule::new(datetime);
   }
   return Schedule::default();
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ring::FollowUpCheck;
+
+  fn heads_up_alarm() -> Alarm {
+    let mut alarm = Alarm::new(1, 1, "0 0 7 * * *", "", false);
+    alarm.heads_up = 600;
+    alarm
+  }
+
+  #[test]
+  fn heads_up_before_next_occurrence() {
+    let after = Utc.ymd(2020, 1, 1).and_hms(6, 0, 0).timestamp();
+    let occurrence = Utc.ymd(2020, 1, 1).and_hms(7, 0, 0).timestamp();
+    let alarm = heads_up_alarm();
+    assert_eq!(
+      get_heads_up_occurrence(&alarm, Utc, after, None, None),
+      occurrence
+    );
+  }
+
+  #[test]
+  fn no_heads_up_once_warned() {
+    let after = Utc.ymd(2020, 1, 1).and_hms(6, 0, 0).timestamp();
+    let mut alarm = heads_up_alarm();
+    alarm.heads_up_sent = Utc.ymd(2020, 1, 1).and_hms(7, 0, 0).timestamp();
+    assert_eq!(get_heads_up_occurrence(&alarm, Utc, after, None, None), -1);
+  }
+
+  #[test]
+  fn no_heads_up_while_following_up() {
+    let after = Utc.ymd(2020, 1, 1).and_hms(6, 0, 0).timestamp();
+    let mut alarm = heads_up_alarm();
+    alarm.ring = RingState::FollowingUp(FollowUpCheck {
+      check_at: after + 300,
+      deadline: None,
+    });
+    assert_eq!(get_heads_up_occurrence(&alarm, Utc, after, None, None), -1);
+  }
+}
@@ -0,0 +1,44 @@
+use crate::alarm::{get_recent_schedule_after, AsAlarmScheduleRef, AsScheduleRef};
+use crate::store::{Alarm, Bedtime, Vacation};
+use chrono::prelude::*;
+
+#[derive(Debug)]
+pub struct BedtimeSchedule<Z: TimeZone> {
+  pub wake: DateTime<Z>,
+  pub bedtime: DateTime<Z>,
+  pub reminder: DateTime<Z>,
+  pub title: String,
+}
+
+/// Works out tonight's bedtime from the first wake alarm after `after`.
+/// Alarms opted out with `is_bedtime_off` are not considered wake alarms, and
+/// occurrences suspended by `vacation` are skipped.
+pub fn get_bedtime<Z>(
+  alarms: &[Alarm],
+  settings: &Bedtime,
+  vacation: Option<&Vacation>,
+  after: &DateTime<Z>,
+) -> Option<BedtimeSchedule<Z>>
+where
+  Z: TimeZone + 'static,
+{
+  let wake_alarms: Vec<Alarm> = alarms
+    .iter()
+    .filter(|alarm| !alarm.is_bedtime_off)
+    .cloned()
+    .collect();
+  let recent = get_recent_schedule_after(&wake_alarms, after, 0, vacation, None);
+  let wake = recent.schedule().to_timestamp();
+  if wake < 0 {
+    return None;
+  }
+  let wake = after.timezone().timestamp(wake, 0);
+  let bedtime = wake.clone() - chrono::Duration::seconds(settings.sleep_duration);
+  let reminder = bedtime.clone() - chrono::Duration::seconds(settings.wind_down);
+  Some(BedtimeSchedule {
+    wake,
+    bedtime,
+    reminder,
+    title: recent.alarm_title(),
+  })
+}
@@ -0,0 +1,366 @@
+use rand::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::str::FromStr;
+
+const ANSWER_MAP: [&str; 4] = [
+  "零一二三四五六七八九十",
+  "〇一二三四五六七八九十",
+  "零壹贰叁肆伍陆柒捌玖拾",
+  "洞幺两三四五六拐怕勾叉",
+];
+const SENTENCES: [&str; 12] = [
+  "早起的鸟儿有虫吃。",
+  "一日之计在于晨。",
+  "少壮不努力，老大徒伤悲。",
+  "天行健，君子以自强不息。",
+  "千里之行，始于足下。",
+  "黑发不知勤学早，白首方悔读书迟。",
+  "盛年不重来，一日难再晨。",
+  "莫等闲，白了少年头，空悲切。",
+  "春眠不觉晓，处处闻啼鸟。",
+  "业精于勤，荒于嬉。",
+  "明日复明日，明日何其多。",
+  "不积跬步，无以至千里。",
+];
+/// Put between the characters of text to retype, so that copying it does not
+/// give the answer.
+const ZERO_WIDTH_SPACE: char = '\u{200b}';
+
+/// The kinds of strict challenges an alarm can ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChallengeKind {
+  /// Transcribing digits into one of the `ANSWER_MAP` numeral sets.
+  Numerals,
+  /// Solving an arithmetic problem.
+  Arithmetic,
+  /// Retyping a random sentence.
+  Sentence,
+  /// Putting a shuffled list of numbers in order.
+  Ordering,
+  /// Sharing a location away from the user's `Home`.
+  Location,
+}
+
+impl Default for ChallengeKind {
+  fn default() -> ChallengeKind {
+    ChallengeKind::Numerals
+  }
+}
+
+impl ChallengeKind {
+  pub fn challenge_type(self) -> &'static dyn ChallengeType {
+    match self {
+      ChallengeKind::Numerals => &Numerals,
+      ChallengeKind::Arithmetic => &Arithmetic,
+      ChallengeKind::Sentence => &Sentence,
+      ChallengeKind::Ordering => &Ordering,
+      ChallengeKind::Location => &Location,
+    }
+  }
+}
+
+impl FromStr for ChallengeKind {
+  type Err = &'static str;
+  fn from_str(s: &str) -> Result<ChallengeKind, &'static str> {
+    match s {
+      "numerals" => Ok(ChallengeKind::Numerals),
+      "math" => Ok(ChallengeKind::Arithmetic),
+      "sentence" => Ok(ChallengeKind::Sentence),
+      "order" => Ok(ChallengeKind::Ordering),
+      "location" => Ok(ChallengeKind::Location),
+      _ => Err("Bad challenge kind"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Difficulty {
+  Easy,
+  Normal,
+  Hard,
+}
+
+impl Default for Difficulty {
+  fn default() -> Difficulty {
+    Difficulty::Normal
+  }
+}
+
+impl FromStr for Difficulty {
+  type Err = &'static str;
+  fn from_str(s: &str) -> Result<Difficulty, &'static str> {
+    match s {
+      "easy" => Ok(Difficulty::Easy),
+      "normal" => Ok(Difficulty::Normal),
+      "hard" => Ok(Difficulty::Hard),
+      _ => Err("Bad difficulty"),
+    }
+  }
+}
+
+/// A challenge issued to a ringing strict alarm, kept in its `Ring` until it
+/// is solved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Challenge {
+  pub kind: ChallengeKind,
+  /// What to do with the prompt.
+  pub instruction: String,
+  /// What the user is shown, set in monospace.
+  pub prompt: String,
+  pub answer: String,
+}
+
+impl Challenge {
+  /// A numeral transcription from an old save, which only kept the answer.
+  pub fn legacy<T>(answer: T) -> Challenge
+  where
+    T: AsRef<str>,
+  {
+    Challenge {
+      kind: ChallengeKind::Numerals,
+      instruction: String::default(),
+      prompt: String::default(),
+      answer: String::from(answer.as_ref()),
+    }
+  }
+  pub fn check(&self, text: &str) -> bool {
+    self.kind.challenge_type().check(self, text)
+  }
+  pub fn hint(&self, text: &str) -> String {
+    self.kind.challenge_type().hint(self, text)
+  }
+}
+
+/// A way of making sure the user is awake before a strict alarm lets go.
+pub trait ChallengeType {
+  fn issue(&self, difficulty: Difficulty, rng: &mut dyn RngCore) -> Challenge;
+  /// Whether `text` solves `challenge`. Surrounding whitespace never counts.
+  fn check(&self, challenge: &Challenge, text: &str) -> bool {
+    text.trim() == challenge.answer
+  }
+  /// Tells what is wrong with `text`, an answer that did not check.
+  fn hint(&self, challenge: &Challenge, text: &str) -> String {
+    let answer: Vec<char> = challenge.answer.chars().collect();
+    let text: Vec<char> = text.trim().chars().collect();
+    let correct = answer
+      .iter()
+      .zip(text.iter())
+      .take_while(|(a, b)| a == b)
+      .count();
+    match correct {
+      correct if correct < answer.len() && correct < text.len() => {
+        format!("前 {} 个字正确，第 {} 个字有误。", correct, correct + 1)
+      }
+      _ if text.len() < answer.len() => format!(
+        "少了 {} 个字，答案共 {} 个字。",
+        answer.len() - text.len(),
+        answer.len()
+      ),
+      _ => format!(
+        "多了 {} 个字，答案共 {} 个字。",
+        text.len() - answer.len(),
+        answer.len()
+      ),
+    }
+  }
+}
+
+pub struct Numerals;
+
+impl ChallengeType for Numerals {
+  fn issue(&self, difficulty: Difficulty, rng: &mut dyn RngCore) -> Challenge {
+    let length = match difficulty {
+      Difficulty::Easy => 15,
+      Difficulty::Normal => 30,
+      Difficulty::Hard => 45,
+    };
+    let map = ANSWER_MAP[rng.gen_range(0, ANSWER_MAP.len())];
+    let numerals: Vec<char> = map.chars().collect();
+    let mut prompt = String::default();
+    let mut answer = String::default();
+    for _ in 0..length {
+      let number = rng.gen_range(0, 11);
+      match number {
+        10 => prompt.push('X'),
+        number => prompt += &number.to_string(),
+      }
+      answer.push(numerals[number]);
+    }
+    Challenge {
+      kind: ChallengeKind::Numerals,
+      instruction: format!("请用汉字“{}”输入下面的数字以关闭闹钟：", map),
+      prompt,
+      answer,
+    }
+  }
+}
+
+pub struct Arithmetic;
+
+impl ChallengeType for Arithmetic {
+  fn issue(&self, difficulty: Difficulty, rng: &mut dyn RngCore) -> Challenge {
+    let (prompt, answer) = match difficulty {
+      Difficulty::Easy => {
+        let (a, b) = (rng.gen_range(10, 100), rng.gen_range(10, 100));
+        (format!("{} + {}", a, b), a + b)
+      }
+      Difficulty::Normal => {
+        let (a, b, c) = (
+          rng.gen_range(10, 100),
+          rng.gen_range(3, 10),
+          rng.gen_range(10, 100),
+        );
+        (format!("{} × {} + {}", a, b, c), a * b + c)
+      }
+      Difficulty::Hard => {
+        let (a, b, c) = (
+          rng.gen_range(10, 100),
+          rng.gen_range(10, 100),
+          rng.gen_range(100, 1000),
+        );
+        (format!("{} × {} - {}", a, b, c), a * b - c)
+      }
+    };
+    Challenge {
+      kind: ChallengeKind::Arithmetic,
+      instruction: String::from("请回复下面算式的结果以关闭闹钟："),
+      prompt,
+      answer: answer.to_string(),
+    }
+  }
+  fn check(&self, challenge: &Challenge, text: &str) -> bool {
+    match (text.trim().parse::<i64>(), challenge.answer.parse::<i64>()) {
+      (Ok(text), Ok(answer)) => text == answer,
+      _ => false,
+    }
+  }
+  fn hint(&self, _: &Challenge, text: &str) -> String {
+    match text.trim().parse::<i64>() {
+      Ok(_) => String::from("结果不对，再算一遍。"),
+      Err(_) => String::from("请只回复一个整数。"),
+    }
+  }
+}
+
+pub struct Sentence;
+
+impl ChallengeType for Sentence {
+  fn issue(&self, difficulty: Difficulty, rng: &mut dyn RngCore) -> Challenge {
+    let count = match difficulty {
+      Difficulty::Easy => 1,
+      Difficulty::Normal => 2,
+      Difficulty::Hard => 3,
+    };
+    let answer: String = SENTENCES
+      .choose_multiple(rng, count)
+      .cloned()
+      .collect::<Vec<&str>>()
+      .concat();
+    let mut prompt = String::default();
+    for c in answer.chars() {
+      if !prompt.is_empty() {
+        prompt.push(ZERO_WIDTH_SPACE);
+      }
+      prompt.push(c);
+    }
+    Challenge {
+      kind: ChallengeKind::Sentence,
+      instruction: String::from("请一字不差地输入下面的句子以关闭闹钟："),
+      prompt,
+      answer,
+    }
+  }
+}
+
+pub struct Ordering;
+
+impl ChallengeType for Ordering {
+  fn issue(&self, difficulty: Difficulty, rng: &mut dyn RngCore) -> Challenge {
+    let count = match difficulty {
+      Difficulty::Easy => 5,
+      Difficulty::Normal => 8,
+      Difficulty::Hard => 12,
+    };
+    let mut numbers: Vec<u32> = (1..100).collect::<Vec<u32>>();
+    numbers.shuffle(rng);
+    numbers.truncate(count);
+    let prompt = join_numbers(&numbers);
+    numbers.sort_unstable();
+    Challenge {
+      kind: ChallengeKind::Ordering,
+      instruction: String::from("请把下面的数字从小到大排列，用空格隔开，回复以关闭闹钟："),
+      prompt,
+      answer: join_numbers(&numbers),
+    }
+  }
+  fn check(&self, challenge: &Challenge, text: &str) -> bool {
+    let numbers: Vec<&str> = text
+      .split(|c: char| c.is_whitespace() || c == ',' || c == '，')
+      .filter(|number| !number.is_empty())
+      .collect();
+    numbers.join(" ") == challenge.answer
+  }
+  fn hint(&self, challenge: &Challenge, text: &str) -> String {
+    let answer: Vec<&str> = challenge.answer.split(' ').collect();
+    let numbers: Vec<&str> = text
+      .split(|c: char| c.is_whitespace() || c == ',' || c == '，')
+      .filter(|number| !number.is_empty())
+      .collect();
+    match answer.iter().zip(numbers.iter()).position(|(a, b)| a != b) {
+      Some(i) => format!("第 {} 个数字放错了。", i + 1),
+      None => format!(
+        "一共有 {} 个数字，你回复了 {} 个。",
+        answer.len(),
+        numbers.len()
+      ),
+    }
+  }
+}
+
+/// Solved by sharing a location far enough from home, which the handler
+/// checks, so no text ever solves it.
+pub struct Location;
+
+impl ChallengeType for Location {
+  fn issue(&self, _: Difficulty, _: &mut dyn RngCore) -> Challenge {
+    Challenge {
+      kind: ChallengeKind::Location,
+      instruction: String::from("请起床走出去，分享你的实时位置，离床足够远时闹钟就会关闭。"),
+      prompt: String::default(),
+      answer: String::default(),
+    }
+  }
+  fn check(&self, _: &Challenge, _: &str) -> bool {
+    false
+  }
+  fn hint(&self, _: &Challenge, _: &str) -> String {
+    String::from("这个挑战要分享实时位置，而不是发文字。")
+  }
+}
+
+fn join_numbers(numbers: &[u32]) -> String {
+  numbers
+    .iter()
+    .map(|number| number.to_string())
+    .collect::<Vec<String>>()
+    .join(" ")
+}
+
+/// Reads a challenge of the current format, or the bare answer string saves
+/// kept before challenges had kinds.
+pub fn deserialize_challenge<'de, D>(deserializer: D) -> Result<Option<Challenge>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum Stored {
+    Legacy(String),
+    Current(Challenge),
+  }
+  let stored: Option<Stored> = Option::deserialize(deserializer)?;
+  Ok(stored.map(|stored| match stored {
+    Stored::Legacy(answer) => Challenge::legacy(answer),
+    Stored::Current(challenge) => challenge,
+  }))
+}
@@ -0,0 +1,444 @@
+use rtdlib::{tdjson::Tdlib, types::Error, types::RObject};
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two requests, whatever chat they go to.
+const GLOBAL_INTERVAL: Duration = Duration::from_millis(50);
+/// Minimum time between two requests to the same chat.
+const CHAT_INTERVAL: Duration = Duration::from_millis(1000);
+/// Low priority requests that waited longer than this are not worth sending.
+const LOW_PRIORITY_TTL: Duration = Duration::from_secs(5);
+
+/// What a tagged request was sent for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+  /// Calling a user, for an alarm, a countdown or an emergency contact.
+  /// `alarm_id` is the alarm the call rings for, if any.
+  Call { user_id: i64, alarm_id: Option<i64> },
+  /// Muting a member of a group or lifting it.
+  ChatMember {
+    chat_id: i64,
+    user_id: i64,
+    is_muting: bool,
+  },
+  /// Sending a message to a chat.
+  Message { chat_id: i64 },
+}
+
+/// TDLib's answer to a request sent through the client, see `take_response`.
+#[derive(Debug, Clone)]
+pub enum Response {
+  /// A tagged request succeeded, with what TDLib answered.
+  Done(Request, Value),
+  /// A request failed. `request` is what it was sent for, `None` if it was
+  /// untagged.
+  Failed {
+    request: Option<Request>,
+    error: Error,
+  },
+  /// Telegram asked to wait, and the request was queued again.
+  Retrying(Option<Request>),
+}
+
+/// Outbound requests to different chats are sent in this order of priority.
+/// Requests to the same chat keep the order they were queued in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+  /// Chat actions and read receipts, dropped when they are stale.
+  Low,
+  /// Messages and everything else.
+  Normal,
+  /// Calls, which are what wakes people up.
+  Call,
+}
+
+/// Counters of the outbound queue since they were last taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+  /// Requests waiting right now.
+  pub depth: usize,
+  pub max_depth: usize,
+  pub sent: u64,
+  /// Stale low priority requests that were never sent.
+  pub dropped: u64,
+  pub flood_waits: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Outgoing {
+  seq: u64,
+  priority: Priority,
+  chat_id: Option<i64>,
+  json: Value,
+  request: Option<Request>,
+  queued_at: Instant,
+}
+
+#[derive(Debug)]
+struct Outbox {
+  queue: Vec<Outgoing>,
+  next_seq: u64,
+  ready_at: Option<Instant>,
+  /// When each chat may get its next request, after `CHAT_INTERVAL`. Calls
+  /// are exempt.
+  chat_ready_at: HashMap<i64, Instant>,
+  /// When each chat may get any request again, after a flood wait.
+  chat_flood_until: HashMap<i64, Instant>,
+  pending: HashMap<String, Outgoing>,
+  metrics: Metrics,
+}
+
+impl Outbox {
+  fn new() -> Outbox {
+    Outbox {
+      queue: vec![],
+      next_seq: 1,
+      ready_at: None,
+      chat_ready_at: HashMap::new(),
+      chat_flood_until: HashMap::new(),
+      pending: HashMap::new(),
+      metrics: Metrics::default(),
+    }
+  }
+  /// Whether no request queued earlier to the same chat is still waiting, so
+  /// that a chat action is never sent after the message it announces. Calls
+  /// do not wait for messages and hold none back.
+  fn is_first_in_chat(&self, outgoing: &Outgoing) -> bool {
+    match outgoing.chat_id {
+      Some(chat_id) if outgoing.priority != Priority::Call => !self.queue.iter().any(|other| {
+        other.chat_id == Some(chat_id)
+          && other.priority != Priority::Call
+          && other.seq < outgoing.seq
+      }),
+      _ => true,
+    }
+  }
+  /// When `outgoing` may be sent, as far as its chat is concerned.
+  fn chat_ready_at(&self, outgoing: &Outgoing) -> Option<Instant> {
+    let chat_id = outgoing.chat_id?;
+    let flood_until = self.chat_flood_until.get(&chat_id).cloned();
+    let ready_at = match outgoing.priority {
+      Priority::Call => None,
+      _ => self.chat_ready_at.get(&chat_id).cloned(),
+    };
+    flood_until.max(ready_at)
+  }
+  fn is_ready(&self, outgoing: &Outgoing, now: Instant) -> bool {
+    match self.chat_ready_at(outgoing) {
+      Some(ready_at) => ready_at <= now,
+      None => true,
+    }
+  }
+  /// Takes the request to send next, or tells how long to wait for one.
+  fn pop(&mut self, now: Instant) -> Result<Outgoing, Option<Duration>> {
+    let len = self.queue.len();
+    self.queue.retain(|outgoing| {
+      outgoing.priority > Priority::Low || now < outgoing.queued_at + LOW_PRIORITY_TTL
+    });
+    self.metrics.dropped += (len - self.queue.len()) as u64;
+    self.metrics.depth = self.queue.len();
+    self.chat_ready_at.retain(|_, ready_at| *ready_at > now);
+    self
+      .chat_flood_until
+      .retain(|_, flood_until| *flood_until > now);
+    if let Some(ready_at) = self.ready_at.filter(|ready_at| *ready_at > now) {
+      return Err(Some(ready_at - now));
+    }
+    let next = self
+      .queue
+      .iter()
+      .enumerate()
+      .filter(|(_, outgoing)| self.is_ready(outgoing, now) && self.is_first_in_chat(outgoing))
+      .max_by_key(|(_, outgoing)| (outgoing.priority, Reverse(outgoing.seq)))
+      .map(|(i, _)| i);
+    match next {
+      Some(i) => {
+        let outgoing = self.queue.remove(i);
+        self.metrics.depth = self.queue.len();
+        Ok(outgoing)
+      }
+      None => Err(
+        self
+          .queue
+          .iter()
+          .filter_map(|outgoing| self.chat_ready_at(outgoing))
+          .min()
+          .map(|ready_at| ready_at - now),
+      ),
+    }
+  }
+  fn push(&mut self, outgoing: Outgoing) {
+    self.queue.push(outgoing);
+    self.metrics.depth = self.queue.len();
+    self.metrics.max_depth = self.metrics.max_depth.max(self.queue.len());
+  }
+  /// Starts the global and per-chat intervals after `outgoing` was sent.
+  fn sent(&mut self, outgoing: &Outgoing, now: Instant) {
+    self.ready_at = Some(now + GLOBAL_INTERVAL);
+    if let Some(chat_id) = outgoing
+      .chat_id
+      .filter(|_| outgoing.priority != Priority::Call)
+    {
+      self.chat_ready_at.insert(chat_id, now + CHAT_INTERVAL);
+    }
+    self.metrics.sent += 1;
+  }
+  /// Queues `outgoing` again after a flood wait, holding back its chat, or
+  /// everything if it has none, until `ready_at`. It keeps its place in the
+  /// order of its chat.
+  fn requeue(&mut self, outgoing: Outgoing, ready_at: Instant) {
+    match outgoing.chat_id {
+      Some(chat_id) => {
+        self.chat_flood_until.insert(chat_id, ready_at);
+      }
+      None => self.ready_at = Some(ready_at),
+    }
+    self.metrics.flood_waits += 1;
+    self.push(outgoing);
+  }
+}
+
+/// Wraps TDLib behind an outbound queue. Requests are sent by `run` in order
+/// of priority across chats and in queue order within one, no faster than
+/// the global and per-chat limits, and a chat, or everything, is held back as
+/// long as Telegram asks after a flood wait.
+/// Calls only wait for the global limit and flood waits, so that messages to
+/// the same chat never hold them back.
+///
+/// Every request is tagged with `@extra`, which TDLib echoes in the response.
+/// That is how flood waits are retried and how responses, errors included,
+/// are routed back to what the request was sent for.
+pub struct Client {
+  tdlib: Tdlib,
+  outbox: Mutex<Outbox>,
+  wakeup: Condvar,
+}
+
+impl Client {
+  pub fn new() -> Client {
+    Client {
+      tdlib: Tdlib::new(),
+      outbox: Mutex::new(Outbox::new()),
+      wakeup: Condvar::new(),
+    }
+  }
+  /// Queues a request whose response nobody waits for.
+  pub fn send(&self, json: &str) {
+    self.enqueue(json, None)
+  }
+  pub fn receive(&self, timeout: f64) -> Option<String> {
+    self.tdlib.receive(timeout)
+  }
+  /// Queues `req` tagged with `request`, see `take_response`.
+  pub fn request<R>(&self, req: &R, request: Request)
+  where
+    R: RObject,
+  {
+    self.enqueue(&req.to_json().expect("Bad JSON"), Some(request))
+  }
+  fn enqueue(&self, json: &str, request: Option<Request>) {
+    let json: Value = serde_json::from_str(json).expect("Bad JSON");
+    let priority = match json.get("@type").and_then(Value::as_str) {
+      Some("createCall") | Some("acceptCall") | Some("discardCall") => Priority::Call,
+      Some("sendChatAction") | Some("viewMessages") => Priority::Low,
+      _ => Priority::Normal,
+    };
+    let chat_id = json
+      .get("chat_id")
+      .or_else(|| json.get("user_id"))
+      .and_then(Value::as_i64);
+    let mut outbox = self.outbox.lock().unwrap();
+    let seq = outbox.next_seq;
+    outbox.next_seq += 1;
+    outbox.push(Outgoing {
+      seq,
+      priority,
+      chat_id,
+      json,
+      request,
+      queued_at: Instant::now(),
+    });
+    self.wakeup.notify_one();
+  }
+  /// Sends queued requests forever, keeping to the rate limits.
+  pub fn run(&self) {
+    loop {
+      let mut outbox = self.outbox.lock().unwrap();
+      let now = Instant::now();
+      let mut outgoing = match outbox.pop(now) {
+        Ok(outgoing) => outgoing,
+        Err(wait) => {
+          let wait = wait.unwrap_or(LOW_PRIORITY_TTL);
+          let _ = self.wakeup.wait_timeout(outbox, wait).unwrap();
+          continue;
+        }
+      };
+      outbox.sent(&outgoing, now);
+      let extra = outgoing.seq.to_string();
+      outgoing.json["@extra"] = Value::String(extra.clone());
+      let json = outgoing.json.to_string();
+      outbox.pending.insert(extra, outgoing);
+      drop(outbox);
+      self.tdlib.send(&json);
+    }
+  }
+  /// Returns the response if `json` answers a request sent through the
+  /// client, which is then forgotten, or queued again after a flood wait.
+  /// Updates and successful answers to untagged requests give `None`, so
+  /// they go through the usual dispatch.
+  pub fn take_response(&self, json: &str) -> Option<Response> {
+    let value: Value = serde_json::from_str(json).ok()?;
+    let extra = value.get("@extra")?.as_str()?;
+    let mut outbox = self.outbox.lock().unwrap();
+    let outgoing = outbox.pending.remove(extra)?;
+    let error: Error = match value.get("@type").and_then(Value::as_str) {
+      Some("error") => serde_json::from_value(value).unwrap_or_default(),
+      _ => {
+        return outgoing
+          .request
+          .map(|request| Response::Done(request, value))
+      }
+    };
+    if let Some(seconds) = flood_wait(&error) {
+      let ready_at = Instant::now() + Duration::from_secs(seconds);
+      let request = outgoing.request;
+      outbox.requeue(outgoing, ready_at);
+      self.wakeup.notify_one();
+      return Some(Response::Retrying(request));
+    }
+    Some(Response::Failed {
+      request: outgoing.request,
+      error,
+    })
+  }
+  /// Returns the counters and starts counting anew.
+  pub fn take_metrics(&self) -> Metrics {
+    let mut outbox = self.outbox.lock().unwrap();
+    let metrics = outbox.metrics;
+    outbox.metrics = Metrics {
+      depth: outbox.queue.len(),
+      max_depth: outbox.queue.len(),
+      ..Metrics::default()
+    };
+    metrics
+  }
+}
+
+impl Default for Client {
+  fn default() -> Client {
+    Client::new()
+  }
+}
+
+/// Seconds to wait before retrying, if `error` is a flood wait. TDLib reports
+/// them as `Too Many Requests: retry after N`.
+pub fn flood_wait(error: &Error) -> Option<u64> {
+  if error.code() != 429 {
+    return None;
+  }
+  let message = error.message();
+  let seconds = match message.rfind(|c: char| !c.is_ascii_digit()) {
+    Some(i) => &message[i + 1..],
+    None => message.as_str(),
+  };
+  seconds.parse::<u64>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn queue(outbox: &mut Outbox, priority: Priority, chat_id: i64, now: Instant) -> u64 {
+    let seq = outbox.next_seq;
+    outbox.next_seq += 1;
+    outbox.push(Outgoing {
+      seq,
+      priority,
+      chat_id: Some(chat_id),
+      json: Value::Null,
+      request: None,
+      queued_at: now,
+    });
+    seq
+  }
+
+  /// Pops the next request and marks it sent, returning its `seq`.
+  fn send(outbox: &mut Outbox, now: Instant) -> Result<u64, Option<Duration>> {
+    let outgoing = outbox.pop(now)?;
+    outbox.sent(&outgoing, now);
+    Ok(outgoing.seq)
+  }
+
+  #[test]
+  fn same_chat_waits_for_chat_interval() {
+    let now = Instant::now();
+    let mut outbox = Outbox::new();
+    let first = queue(&mut outbox, Priority::Normal, 1, now);
+    let second = queue(&mut outbox, Priority::Normal, 1, now);
+    let other = queue(&mut outbox, Priority::Normal, 2, now);
+    assert_eq!(send(&mut outbox, now), Ok(first));
+    let now = now + GLOBAL_INTERVAL;
+    assert_eq!(send(&mut outbox, now), Ok(other));
+    let now = now + GLOBAL_INTERVAL;
+    assert_eq!(
+      send(&mut outbox, now),
+      Err(Some(CHAT_INTERVAL - GLOBAL_INTERVAL * 2))
+    );
+    let now = now + CHAT_INTERVAL;
+    assert_eq!(send(&mut outbox, now), Ok(second));
+  }
+
+  #[test]
+  fn chat_action_is_not_overtaken_by_its_message() {
+    let now = Instant::now();
+    let mut outbox = Outbox::new();
+    let typing = queue(&mut outbox, Priority::Low, 1, now);
+    let message = queue(&mut outbox, Priority::Normal, 1, now);
+    assert_eq!(send(&mut outbox, now), Ok(typing));
+    assert_eq!(send(&mut outbox, now + CHAT_INTERVAL), Ok(message));
+  }
+
+  #[test]
+  fn call_goes_ahead_of_messages_to_the_same_chat() {
+    let now = Instant::now();
+    let mut outbox = Outbox::new();
+    let message = queue(&mut outbox, Priority::Normal, 1, now);
+    let call = queue(&mut outbox, Priority::Call, 1, now);
+    assert_eq!(send(&mut outbox, now), Ok(call));
+    assert_eq!(send(&mut outbox, now + GLOBAL_INTERVAL), Ok(message));
+  }
+
+  #[test]
+  fn stale_low_priority_requests_are_dropped() {
+    let now = Instant::now();
+    let mut outbox = Outbox::new();
+    queue(&mut outbox, Priority::Low, 1, now);
+    let message = queue(&mut outbox, Priority::Normal, 2, now);
+    let now = now + LOW_PRIORITY_TTL;
+    assert_eq!(send(&mut outbox, now), Ok(message));
+    assert_eq!(outbox.metrics.dropped, 1);
+    assert_eq!(send(&mut outbox, now + GLOBAL_INTERVAL), Err(None));
+  }
+
+  #[test]
+  fn flood_wait_requeues_in_original_order() {
+    let now = Instant::now();
+    let mut outbox = Outbox::new();
+    let first = queue(&mut outbox, Priority::Normal, 1, now);
+    let second = queue(&mut outbox, Priority::Normal, 1, now);
+    let outgoing = outbox.pop(now).unwrap();
+    outbox.sent(&outgoing, now);
+    let flood_until = now + Duration::from_secs(10);
+    outbox.requeue(outgoing, flood_until);
+    assert_eq!(outbox.metrics.flood_waits, 1);
+    assert_eq!(
+      send(&mut outbox, now + CHAT_INTERVAL),
+      Err(Some(flood_until - (now + CHAT_INTERVAL)))
+    );
+    assert_eq!(send(&mut outbox, flood_until), Ok(first));
+    assert_eq!(send(&mut outbox, flood_until + CHAT_INTERVAL), Ok(second));
+  }
+}
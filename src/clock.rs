@@ -0,0 +1,46 @@
+use chrono::prelude::*;
+use std::sync::Mutex;
+
+pub trait Clock: Send + Sync {
+  fn now(&self) -> DateTime<Utc>;
+  fn timestamp(&self) -> i64 {
+    self.now().timestamp()
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> DateTime<Utc> {
+    Utc::now()
+  }
+}
+
+/// A clock that only moves when told to, for driving the scheduler and the
+/// parsers deterministically.
+#[derive(Debug)]
+pub struct ManualClock {
+  now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+  pub fn new(now: DateTime<Utc>) -> ManualClock {
+    ManualClock {
+      now: Mutex::new(now),
+    }
+  }
+  pub fn set(&self, now: DateTime<Utc>) {
+    *self.now.lock().unwrap() = now;
+  }
+  pub fn advance(&self, duration: chrono::Duration) {
+    let mut now = self.now.lock().unwrap();
+    *now = *now + duration;
+  }
+}
+
+impl Clock for ManualClock {
+  fn now(&self) -> DateTime<Utc> {
+    *self.now.lock().unwrap()
+  }
+}
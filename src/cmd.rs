@@ -1,5 +1,7 @@
 extern crate cron;
+use crate::clock::Clock;
 use crate::fmt::*;
+use crate::scheduler::Scheduler;
 use crate::store::{Alarm, Store};
 use chrono::{self, prelude::*};
 use cron::Schedule;
@@ -64,7 +66,7 @@ where
   }
 }
 
-fn test_time_str<T, Z>(input: T, tz: &Z) -> Result<String, &'static str>
+fn test_time_str<T, Z>(input: T, tz: &Z, clock: &dyn Clock) -> Result<String, &'static str>
 where
   T: AsRef<str>,
   Z: TimeZone,
@@ -103,7 +105,7 @@ where
   };
   match day_str {
     "once" => {
-      let now = chrono::Local::now().with_timezone(tz);
+      let now = clock.now().with_timezone(tz);
       let fmt_str = format!("%Y-%m-%d {}:{}:00 %z", h, m);
       let today_alarm_str = now.format(fmt_str.as_str()).to_string();
       let today_alarm_time =
@@ -134,7 +136,11 @@ where
   }
 }
 
-pub fn parse_alarm_args<'a, Z>(input: &'a str, tz: &Z) -> Result<CronArgs<'a>, &'static str>
+pub fn parse_alarm_args<'a, Z>(
+  input: &'a str,
+  tz: &Z,
+  clock: &dyn Clock,
+) -> Result<CronArgs<'a>, &'static str>
 where
   Z: TimeZone,
   Z::Offset: Display,
@@ -148,7 +154,7 @@ where
     Some(first_hash) => &input[..first_hash],
     None => input,
   });
-  let time_str = test_time_str(alarm_str.as_str(), tz);
+  let time_str = test_time_str(alarm_str.as_str(), tz, clock);
   if let Ok(time_str) = time_str {
     alarm_str = String::from(time_str)
   }
@@ -159,11 +165,174 @@ where
   })
 }
 
-pub fn with_alarm_id<T>(store: &Store, user_id: i64, cmd: &Command, f: T) -> InputMessageContent
+/// Parses durations such as `8h`, `30m`, `1h30m` or `90s` into seconds. A
+/// bare number is taken as minutes.
+pub fn parse_duration<T>(input: T) -> Result<i64, &'static str>
+where
+  T: AsRef<str>,
+{
+  let input = input.as_ref().trim();
+  if input.is_empty() {
+    return Err("Bad duration: Missing duration");
+  }
+  if let Ok(minutes) = input.parse::<i64>() {
+    if minutes < 0 {
+      return Err("Bad duration: Duration must not be negative");
+    }
+    return minutes.checked_mul(60).ok_or("Bad duration: Too long");
+  }
+  if input.chars().all(|c| c.is_ascii_digit()) {
+    return Err("Bad duration: Too long");
+  }
+  let mut seconds: i64 = 0;
+  let mut number = String::default();
+  for c in input.chars() {
+    let unit = match c {
+      '0'..='9' => {
+        number.push(c);
+        continue;
+      }
+      'd' => 86400,
+      'h' => 3600,
+      'm' => 60,
+      's' => 1,
+      _ => return Err("Bad duration: Unknown unit"),
+    };
+    if number.is_empty() {
+      return Err("Bad duration: Missing number before unit");
+    }
+    seconds = number
+      .parse::<i64>()
+      .ok()
+      .and_then(|n| n.checked_mul(unit))
+      .and_then(|n| seconds.checked_add(n))
+      .ok_or("Bad duration: Too long")?;
+    number.clear();
+  }
+  if !number.is_empty() {
+    return Err("Bad duration: Missing unit");
+  }
+  Ok(seconds)
+}
+
+/// Parses when a timed `#sleep!` ends into a timestamp: `until 07:30` is the
+/// next time the clock in `tz` shows 07:30 after `now`, anything else is a
+/// duration from `now` as read by `parse_duration`.
+pub fn parse_sleep_until<Z>(input: &str, tz: Z, now: i64) -> Result<i64, &'static str>
+where
+  Z: TimeZone,
+{
+  let args = parse_command_msg(input);
+  if args.cmd() != "until" {
+    return parse_duration(input)
+      .and_then(|duration| now.checked_add(duration).ok_or("Bad duration: Too long"));
+  }
+  let time = match chrono::NaiveTime::parse_from_str(args.arg().trim(), "%H:%M") {
+    Err(_) => return Err("Bad sleep: Times must look like 07:30"),
+    Ok(time) => time,
+  };
+  let today = tz.timestamp(now, 0).naive_local().date();
+  for date in [today, today.succ()].iter() {
+    let until = tz
+      .from_local_datetime(&date.and_time(time))
+      .earliest()
+      .map(|until| until.timestamp());
+    match until {
+      Some(until) if until > now => return Ok(until),
+      _ => continue,
+    }
+  }
+  Err("Bad sleep: Time does not exist")
+}
+
+/// Parses a daily window such as `23:00-07:00` into its start and end in
+/// minutes after midnight.
+pub fn parse_time_window<T>(input: T) -> Result<(u32, u32), &'static str>
+where
+  T: AsRef<str>,
+{
+  let parse_time = |input: &str| -> Result<u32, &'static str> {
+    let time = chrono::NaiveTime::parse_from_str(input.trim(), "%H:%M");
+    match time {
+      Err(_) => Err("Bad time window: Times must look like 23:00"),
+      Ok(time) => Ok(time.hour() * 60 + time.minute()),
+    }
+  };
+  let mut parts = input.as_ref().splitn(2, '-');
+  let start = parse_time(parts.next().unwrap_or_default())?;
+  let end = match parts.next() {
+    None => return Err("Bad time window: Missing end"),
+    Some(end) => parse_time(end)?,
+  };
+  if start == end {
+    return Err("Bad time window: Window is empty");
+  }
+  Ok((start, end))
+}
+
+/// Picks the `#tag` words out of an alarm title, lowercased and without the
+/// `#`. `#work#gym` counts as two tags.
+pub fn parse_tags<T>(input: T) -> Vec<String>
+where
+  T: AsRef<str>,
+{
+  let mut tags: Vec<String> = vec![];
+  for word in input.as_ref().split_whitespace() {
+    if !word.starts_with('#') {
+      continue;
+    }
+    for tag in word.split('#').skip(1) {
+      let tag = tag.to_lowercase();
+      if !tag.is_empty() && !tags.contains(&tag) {
+        tags.push(tag);
+      }
+    }
+  }
+  tags
+}
+
+pub fn with_alarm_id<T>(
+  store: &Store,
+  scheduler: &Scheduler,
+  user_id: i64,
+  cmd: &Command,
+  f: T,
+) -> InputMessageContent
 where
   T: Fn(&mut Vec<Alarm>, usize) -> InputMessageContent,
 {
-  let id = cmd.arg().parse::<usize>();
+  with_alarm_id_arg(store, scheduler, user_id, cmd.arg(), |alarms, id, _| {
+    f(alarms, id)
+  })
+}
+
+/// Like `with_alarm_id`, for commands of the form `#cmd <id> <arg>`. The part
+/// after the id is handed to `f`.
+pub fn with_alarm_id_and_arg<T>(
+  store: &Store,
+  scheduler: &Scheduler,
+  user_id: i64,
+  cmd: &Command,
+  f: T,
+) -> InputMessageContent
+where
+  T: Fn(&mut Vec<Alarm>, usize, &str) -> InputMessageContent,
+{
+  with_alarm_id_arg(store, scheduler, user_id, cmd.arg(), f)
+}
+
+fn with_alarm_id_arg<T>(
+  store: &Store,
+  scheduler: &Scheduler,
+  user_id: i64,
+  input: &str,
+  f: T,
+) -> InputMessageContent
+where
+  T: Fn(&mut Vec<Alarm>, usize, &str) -> InputMessageContent,
+{
+  let args = parse_command_msg(input);
+  let id = args.cmd().parse::<usize>();
   if let Err(_) = id {
     return build_fmt_message(|f| f_bad_arguments(f, "闹钟编号格式有误。"));
   }
@@ -179,11 +348,53 @@ where
         if id >= alarms.len() {
           build_fmt_message(|f| f_bad_arguments(f, "没有这个编号的闹钟。"))
         } else {
-          f(&mut alarms, id)
+          f(&mut alarms, id, args.arg())
         }
       }
     }
   };
   store.save().expect("Failed to save state");
+  scheduler.touch(user_id);
   return to_send;
 }
+
+/// Runs `f` on every alarm of the user tagged with `tag`, which changes the
+/// alarm and returns true, or leaves it as is and returns false. Returns how
+/// many alarms carry the tag and how many of them were changed.
+pub fn with_alarm_tag<T>(
+  store: &Store,
+  scheduler: &Scheduler,
+  user_id: i64,
+  tag: &str,
+  f: T,
+) -> (usize, usize)
+where
+  T: Fn(&mut Alarm) -> bool,
+{
+  let (matched, changed) = {
+    let state = store.state();
+    let alarms_map = state.alarms.borrow();
+    match alarms_map.get(&user_id) {
+      None => (0, 0),
+      Some(alarms) => {
+        let mut matched = 0;
+        let mut changed = 0;
+        for alarm in alarms.borrow_mut().iter_mut() {
+          if !alarm.has_tag(tag) {
+            continue;
+          }
+          matched += 1;
+          if f(alarm) {
+            changed += 1;
+          }
+        }
+        (matched, changed)
+      }
+    }
+  };
+  if changed > 0 {
+    store.save().expect("Failed to save state");
+    scheduler.touch(user_id);
+  }
+  (matched, changed)
+}
@@ -0,0 +1,109 @@
+use crate::store::{Countdown, Pomodoro};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CountdownEvent {
+  /// A plain timer ran out.
+  Finished,
+  /// A work phase ended and a break started.
+  BreakStarted,
+  /// A break ended and the next work phase started.
+  WorkStarted,
+  /// The last work phase of a pomodoro session ended.
+  SessionFinished,
+}
+
+impl CountdownEvent {
+  pub fn is_final(self) -> bool {
+    self == CountdownEvent::Finished || self == CountdownEvent::SessionFinished
+  }
+}
+
+impl Countdown {
+  pub fn timer<T>(
+    id: i64,
+    chat_id: i64,
+    title: T,
+    duration: i64,
+    is_calling: bool,
+    now: i64,
+  ) -> Countdown
+  where
+    T: AsRef<str>,
+  {
+    Countdown {
+      id,
+      chat_id,
+      title: String::from(title.as_ref()),
+      ends_at: now + duration,
+      is_calling,
+      pomodoro: None,
+    }
+  }
+  pub fn pomodoro(
+    id: i64,
+    chat_id: i64,
+    work: i64,
+    rest: i64,
+    rounds: i64,
+    is_calling: bool,
+    now: i64,
+  ) -> Countdown {
+    Countdown {
+      id,
+      chat_id,
+      title: String::default(),
+      ends_at: now + work,
+      is_calling,
+      pomodoro: Some(Pomodoro {
+        work,
+        rest,
+        rounds,
+        round: 1,
+        is_resting: false,
+        focused: 0,
+      }),
+    }
+  }
+  /// Moves the countdown on when its current phase has run out. Phases are
+  /// chained from the previous end rather than from now, so a late tick does
+  /// not stretch the session.
+  pub fn advance(&mut self) -> CountdownEvent {
+    let pomodoro = match &mut self.pomodoro {
+      None => return CountdownEvent::Finished,
+      Some(pomodoro) => pomodoro,
+    };
+    if pomodoro.is_resting {
+      pomodoro.is_resting = false;
+      pomodoro.round += 1;
+      self.ends_at += pomodoro.work;
+      return CountdownEvent::WorkStarted;
+    }
+    pomodoro.focused += pomodoro.work;
+    if pomodoro.round >= pomodoro.rounds {
+      return CountdownEvent::SessionFinished;
+    }
+    pomodoro.is_resting = true;
+    self.ends_at += pomodoro.rest;
+    CountdownEvent::BreakStarted
+  }
+  /// Seconds spent focusing so far, counting the running work phase.
+  pub fn focused(&self, now: i64) -> i64 {
+    match &self.pomodoro {
+      None => 0,
+      Some(pomodoro) if pomodoro.is_resting => pomodoro.focused,
+      Some(pomodoro) => {
+        let started_at = self.ends_at - pomodoro.work;
+        pomodoro.focused + (now - started_at).max(0).min(pomodoro.work)
+      }
+    }
+  }
+}
+
+pub fn next_countdown_id(countdowns: &[Countdown]) -> i64 {
+  countdowns
+    .iter()
+    .map(|countdown| countdown.id)
+    .max()
+    .unwrap_or(0)
+    + 1
+}
@@ -1,22 +1,171 @@
-use chrono::prelude::*;
+use crate::clock::Clock;
+use std::sync::Arc;
+
+/// How far back a tick may reach after the clock leaps forward. Anything due
+/// before `now - MAX_CATCH_UP` is considered missed and is not replayed.
+pub const MAX_CATCH_UP: i64 = 600;
+
+/// Upper bound in seconds on how long the cron thread sleeps between ticks,
+/// so that clock jumps are noticed even when no alarm is due.
+pub const MAX_SLEEP: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockJump {
+  None,
+  /// The clock is behind the last tick by this many seconds. Ticks get an
+  /// empty range until the clock passes the last tick again, so that nothing
+  /// in the replayed range rings twice, while changed users are still
+  /// rescheduled.
+  Backward(i64),
+  /// The clock went forward by this many seconds, more than `MAX_CATCH_UP`.
+  /// Only the last `MAX_CATCH_UP` seconds are handed to the tick.
+  Forward(i64),
+}
 
-#[derive(Debug)]
 pub struct CronService {
-  last_tick: DateTime<Local>,
+  clock: Arc<dyn Clock>,
+  /// The latest time handed to a tick, which never goes back.
+  last_tick: i64,
+  /// The time the clock showed at the previous tick.
+  last_seen: i64,
 }
 
 impl CronService {
-  pub fn new() -> CronService {
+  pub fn new(clock: Arc<dyn Clock>) -> CronService {
+    let last_tick = clock.timestamp();
     CronService {
-      last_tick: Local::now(),
+      clock,
+      last_tick,
+      last_seen: last_tick,
     }
   }
-  pub fn tick<T>(&mut self, f: T)
+  pub fn last_tick(&self) -> i64 {
+    self.last_tick
+  }
+  pub fn tick<T>(&mut self, f: T) -> ClockJump
   where
     T: Fn(i64, i64),
   {
-    let now = Local::now();
-    f(self.last_tick.timestamp(), now.timestamp());
+    let now = self.clock.timestamp();
+    let elapsed = now - self.last_tick;
+    if now < self.last_seen {
+      println!(
+        "[{}] Clock jumped backwards by {}s, nothing is due until it passes {}",
+        now,
+        self.last_seen - now,
+        self.last_tick
+      );
+    }
+    self.last_seen = now;
+    if elapsed < 0 {
+      f(self.last_tick, self.last_tick);
+      return ClockJump::Backward(-elapsed);
+    }
+    let jump = if elapsed > MAX_CATCH_UP {
+      println!(
+        "[{}] Clock jumped forwards by {}s, alarms due in ({}, {}] are missed",
+        now,
+        elapsed,
+        self.last_tick,
+        now - MAX_CATCH_UP
+      );
+      f(now - MAX_CATCH_UP, now);
+      ClockJump::Forward(elapsed)
+    } else {
+      f(self.last_tick, now);
+      ClockJump::None
+    };
     self.last_tick = now;
+    jump
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::clock::ManualClock;
+  use chrono::{Duration, TimeZone, Utc};
+  use std::cell::RefCell;
+
+  fn start() -> (Arc<ManualClock>, CronService) {
+    let clock = Arc::new(ManualClock::new(Utc.ymd(2020, 1, 1).and_hms(0, 0, 0)));
+    let service = CronService::new(clock.clone());
+    (clock, service)
+  }
+
+  /// Ticks once, returning the jump and the ranges handed to the tick.
+  fn tick(service: &mut CronService) -> (ClockJump, Vec<(i64, i64)>) {
+    let ranges = RefCell::new(vec![]);
+    let jump = service.tick(|last_tick, now| ranges.borrow_mut().push((last_tick, now)));
+    (jump, ranges.into_inner())
+  }
+
+  #[test]
+  fn tick_covers_elapsed_time() {
+    let (clock, mut service) = start();
+    let start = clock.timestamp();
+    clock.advance(Duration::seconds(30));
+    assert_eq!(
+      tick(&mut service),
+      (ClockJump::None, vec![(start, start + 30)])
+    );
+    assert_eq!(service.last_tick(), start + 30);
+  }
+
+  #[test]
+  fn tick_catches_up_to_max_catch_up() {
+    let (clock, mut service) = start();
+    let start = clock.timestamp();
+    clock.advance(Duration::seconds(MAX_CATCH_UP));
+    assert_eq!(
+      tick(&mut service),
+      (ClockJump::None, vec![(start, start + MAX_CATCH_UP)])
+    );
+  }
+
+  #[test]
+  fn forward_jump_past_max_catch_up_misses_the_rest() {
+    let (clock, mut service) = start();
+    let start = clock.timestamp();
+    clock.advance(Duration::seconds(3600));
+    let now = start + 3600;
+    assert_eq!(
+      tick(&mut service),
+      (ClockJump::Forward(3600), vec![(now - MAX_CATCH_UP, now)])
+    );
+    assert_eq!(service.last_tick(), now);
+    clock.advance(Duration::seconds(10));
+    assert_eq!(tick(&mut service), (ClockJump::None, vec![(now, now + 10)]));
+  }
+
+  #[test]
+  fn backward_jump_ticks_empty_ranges_until_the_clock_catches_up() {
+    let (clock, mut service) = start();
+    let start = clock.timestamp();
+    clock.advance(Duration::seconds(-60));
+    assert_eq!(
+      tick(&mut service),
+      (ClockJump::Backward(60), vec![(start, start)])
+    );
+    assert_eq!(service.last_tick(), start);
+    clock.advance(Duration::seconds(30));
+    assert_eq!(
+      tick(&mut service),
+      (ClockJump::Backward(30), vec![(start, start)])
+    );
+    clock.advance(Duration::seconds(30));
+    assert_eq!(tick(&mut service), (ClockJump::None, vec![(start, start)]));
+    clock.advance(Duration::seconds(10));
+    assert_eq!(
+      tick(&mut service),
+      (ClockJump::None, vec![(start, start + 10)])
+    );
+  }
+
+  #[test]
+  fn unchanged_clock_ticks_an_empty_range() {
+    let (clock, mut service) = start();
+    let start = clock.timestamp();
+    assert_eq!(tick(&mut service), (ClockJump::None, vec![(start, start)]));
   }
 }
@@ -1,18 +1,22 @@
 use crate::alarm::{get_next_schedule, AsScheduleRef};
-use crate::store::Alarm;
-use chrono::TimeZone;
-use rand::prelude::*;
+use crate::bedtime::BedtimeSchedule;
+use crate::challenge::{Challenge, ChallengeKind, Difficulty};
+use crate::clock::Clock;
+use crate::cmd::parse_tags;
+use crate::ring::{Fallback, RingChannel};
+use crate::stats::Standing;
+use crate::store::{
+  Alarm, Bedtime, Countdown, EmergencyContact, IncomingCall, QuietHours, SharedAlarm, Vacation,
+  WakeOutcome,
+};
+use chrono::{DateTime, TimeZone};
 use rtdlib::types::*;
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::fmt::Display;
 
 const HELP_TEXT: &str = "点击查看帮助。";
 const HELP_URL: &str = "https://telegra.ph/%E4%BD%BF%E7%94%A8%E5%B8%AE%E5%8A%A9-11-29";
-const ANSWER_MAP: [&'static str; 4] = [
-  "零一二三四五六七八九十",
-  "〇一二三四五六七八九十",
-  "零壹贰叁肆伍陆柒捌玖拾",
-  "洞幺两三四五六拐怕勾叉",
-];
 
 pub fn build_fmt_message<T>(f: T) -> InputMessageContent
 where
@@ -40,6 +44,371 @@ where
   )
 }
 
+pub fn format_duration(seconds: i64) -> String {
+  let mut parts = vec![];
+  if seconds >= 3600 {
+    parts.push(format!("{} 小时", seconds / 3600));
+  }
+  if seconds % 3600 >= 60 {
+    parts.push(format!("{} 分钟", seconds % 3600 / 60));
+  }
+  if seconds % 60 > 0 || parts.is_empty() {
+    parts.push(format!("{} 秒", seconds % 60));
+  }
+  parts.join(" ")
+}
+
+pub fn fmt_bedtime<Z>(bedtime: &BedtimeSchedule<Z>, settings: &Bedtime) -> String
+where
+  Z: TimeZone,
+  Z::Offset: Display,
+{
+  format!(
+    "{} 入睡可以睡够 {}，{} 的闹钟{}会叫醒你。",
+    bedtime.bedtime.format("%R"),
+    format_duration(settings.sleep_duration),
+    bedtime.wake.format("%F %R%:z"),
+    match bedtime.title.as_str() {
+      "" => String::default(),
+      title => format!(" {} ", title),
+    }
+  )
+}
+
+/// The heads-up before `occurrence` of the alarm numbered `id` in the list
+/// of its user.
+pub fn fmt_heads_up<Z>(alarm: &Alarm, id: usize, occurrence: &DateTime<Z>) -> String
+where
+  Z: TimeZone,
+  Z::Offset: Display,
+{
+  format!(
+    "{} 的闹钟{}将在 {}后响，不需要的话可以用 #disalarm {} once 取消这一次。",
+    occurrence.format("%F %R"),
+    match alarm.title.as_str() {
+      "" => String::from(" "),
+      title => format!(" {} ", title),
+    },
+    format_duration(alarm.heads_up),
+    id
+  )
+}
+
+pub fn fmt_quiet_hours(quiet_hours: &QuietHours) -> String {
+  format!(
+    "免打扰时段为 {:02}:{:02}-{:02}:{:02}，期间的非严格闹钟{}。",
+    quiet_hours.start / 60,
+    quiet_hours.start % 60,
+    quiet_hours.end / 60,
+    quiet_hours.end % 60,
+    match quiet_hours.is_dropping {
+      true => "不会响",
+      false => "会推迟到时段结束时再响",
+    }
+  )
+}
+
+pub fn fmt_ring_channel(channel: RingChannel) -> &'static str {
+  match channel {
+    RingChannel::Call => "电话",
+    RingChannel::Message => "消息",
+    RingChannel::Pin => "置顶消息",
+    RingChannel::VoiceNote => "语音",
+  }
+}
+
+pub fn fmt_fallback(fallback: &Fallback) -> String {
+  let chain: Vec<&str> = fallback
+    .chain
+    .iter()
+    .map(|channel| fmt_ring_channel(*channel))
+    .collect();
+  format!(
+    "电话连续失败 {} 次后依次改用{}提醒，每种方式失败 {} 次后换下一种。",
+    fallback.after,
+    chain.join("、"),
+    fallback.after
+  )
+}
+
+/// Text of the check of a follow-up.
+pub fn fmt_follow_up(alarm: &Alarm, window: i64) -> String {
+  format!(
+    "还醒着吗？请在 {}内回复任意消息，否则闹钟{}会重新响起。",
+    format_duration(window),
+    match alarm.title.as_str() {
+      "" => String::default(),
+      title => format!(" {} ", title),
+    }
+  )
+}
+
+/// Text of a ring on a fallback channel.
+pub fn fmt_fallback_ring(alarm: &Alarm) -> String {
+  format!(
+    "闹钟{}在响，但是电话打不通。{}",
+    match alarm.title.as_str() {
+      "" => String::default(),
+      title => format!(" {} ", title),
+    },
+    match alarm.is_strict {
+      true => "完成下面的挑战来关闭闹钟。",
+      false => "回复任意消息来关闭闹钟。",
+    }
+  )
+}
+
+/// The leaderboard of a group, or its weekly summary, under `title`.
+pub fn fmt_standings(
+  title: &str,
+  standings: &[Standing],
+  users_map: &HashMap<i64, String>,
+) -> String {
+  if standings.is_empty() {
+    return format!("{}\n还没有群友用群里的闹钟起床。", title);
+  }
+  let mut lines = vec![String::from(title)];
+  for (i, standing) in standings.iter().enumerate() {
+    let [first_call, retried, helped, failed] = standing.outcomes;
+    lines.push(format!(
+      "{}. {} 连续起床 {} 次（最多 {} 次），一次叫醒 {}，多次叫醒 {}，群友帮忙 {}，没起来 {}{}",
+      i + 1,
+      users_map
+        .get(&standing.user_id)
+        .map(|name| name.as_str())
+        .unwrap_or("TA"),
+      standing.streak,
+      standing.best_streak,
+      first_call,
+      retried,
+      helped,
+      failed,
+      match standing.average_time {
+        None => String::default(),
+        Some(average_time) => format!("，平均 {}起床", format_duration(average_time)),
+      }
+    ));
+  }
+  lines.join("\n")
+}
+
+pub fn fmt_wake_outcome(outcome: Option<WakeOutcome>) -> &'static str {
+  match outcome {
+    Some(WakeOutcome::FirstCall) => "一次叫醒",
+    Some(WakeOutcome::Retried) => "多次叫醒",
+    Some(WakeOutcome::Helped) => "群友帮忙",
+    Some(WakeOutcome::Failed) => "没起来",
+    None => "没有回应",
+  }
+}
+
+pub fn fmt_shared_alarm(shared: &SharedAlarm) -> String {
+  match shared.title.as_str() {
+    "" => format!("[{}]", shared.id),
+    title => format!("[{}] {}", shared.id, title),
+  }
+}
+
+/// The shared alarms of a group and who joined them, see `#shared`.
+pub fn fmt_shared_alarms(
+  shared_alarms: &[SharedAlarm],
+  users_map: &HashMap<i64, String>,
+) -> String {
+  if shared_alarms.is_empty() {
+    return String::from("本群还没有共享闹钟，使用 #shared <闹钟参数> 设置一个。");
+  }
+  let mut lines = vec![String::from("本群的共享闹钟：")];
+  for shared in shared_alarms.iter() {
+    let names: Vec<&str> = shared
+      .subscribers
+      .iter()
+      .map(|user_id| {
+        users_map
+          .get(user_id)
+          .map(|name| name.as_str())
+          .unwrap_or("TA")
+      })
+      .collect();
+    let cron = shared.cron.get(2..).unwrap_or(&shared.cron);
+    lines.push(format!(
+      "{} {}，成员：{}",
+      fmt_shared_alarm(shared),
+      cron,
+      names.join("、")
+    ));
+  }
+  lines.join("\n")
+}
+
+/// How the members of a shared alarm responded to an occurrence.
+pub fn fmt_shared_summary(
+  shared: &SharedAlarm,
+  responses: &[(i64, Option<WakeOutcome>)],
+  users_map: &HashMap<i64, String>,
+) -> String {
+  let awake = responses
+    .iter()
+    .filter(|(_, outcome)| outcome.is_some() && *outcome != Some(WakeOutcome::Failed))
+    .count();
+  let mut lines = vec![format!(
+    "共享闹钟 {}：{}/{} 人已起床。",
+    fmt_shared_alarm(shared),
+    awake,
+    responses.len()
+  )];
+  for (user_id, outcome) in responses.iter() {
+    lines.push(format!(
+      "{}：{}",
+      users_map
+        .get(user_id)
+        .map(|name| name.as_str())
+        .unwrap_or("TA"),
+      fmt_wake_outcome(*outcome)
+    ));
+  }
+  lines.join("\n")
+}
+
+/// Members of a group muted by `#sleep!`, with when each mute is lifted.
+pub fn fmt_sleepers(
+  sleepers: &[(i64, Option<i64>)],
+  users_map: &HashMap<i64, String>,
+  now: i64,
+) -> String {
+  if sleepers.is_empty() {
+    return String::from("群里没有人在睡觉。");
+  }
+  let mut lines = vec![String::from("正在睡觉的群友：")];
+  for (user_id, until) in sleepers.iter() {
+    let name = users_map
+      .get(user_id)
+      .map(|name| name.as_str())
+      .unwrap_or("TA");
+    lines.push(match until {
+      None => format!("{} 直到闹钟关闭", name),
+      Some(until) => format!(
+        "{} 还有 {}解除禁言",
+        name,
+        format_duration((until - now).max(0))
+      ),
+    });
+  }
+  lines.join("\n")
+}
+
+pub fn fmt_contact(contact: &EmergencyContact) -> String {
+  format!(
+    "闹钟连续 {} 次没人接时会{}紧急联系人{}。",
+    contact.after,
+    match contact.is_calling {
+      true => "打电话给",
+      false => "发消息给",
+    },
+    match contact.invitee.parse::<i64>() {
+      _ if contact.invitee.is_empty() => String::default(),
+      Ok(_) => format!(" {}", contact.invitee),
+      Err(_) => format!(" @{}", contact.invitee),
+    }
+  )
+}
+
+/// Alert sent to the emergency contact of `user_id`.
+pub fn f_emergency_alert<T>(
+  f: &mut RTDFormattedTextBuilder,
+  name: T,
+  user_id: i64,
+  alarm: &Alarm,
+  attempts: i64,
+) where
+  T: AsRef<str>,
+{
+  let name = name.as_ref();
+  let mut text = String::from("紧急联系人你好，");
+  let mention = TextEntityTypeMentionName::builder()
+    .user_id(user_id)
+    .build();
+  let mention_entity = TextEntity::builder()
+    .type_(TextEntityType::MentionName(mention))
+    .offset(text.encode_utf16().count().try_into().unwrap())
+    .length(name.encode_utf16().count().try_into().unwrap())
+    .build();
+  text += name;
+  text += &format!(
+    " 的闹钟{}已经响了 {} 次还没叫醒，快想办法叫一下！叫醒之后发送 #ack {} 停止提醒。",
+    match alarm.title.as_str() {
+      "" => String::default(),
+      title => format!(" {} ", title),
+    },
+    attempts,
+    user_id
+  );
+  f.text(text);
+  f.entities(vec![mention_entity]);
+}
+
+pub fn fmt_incoming_call(action: IncomingCall) -> &'static str {
+  match action {
+    IncomingCall::Ignore => "打电话给我不会有任何作用。",
+    IncomingCall::Snooze => {
+      "闹钟响的时候打电话给我可以推迟 10 分钟，没有闹钟在响时会告诉你下次闹钟时间。"
+    }
+    IncomingCall::Dismiss => {
+      "闹钟响的时候打电话给我可以关闭非严格模式的闹钟，严格模式的闹钟会推迟 10 分钟。"
+    }
+  }
+}
+
+pub fn fmt_vacation<Z>(vacation: &Vacation, tz: Z) -> String
+where
+  Z: TimeZone,
+  Z::Offset: Display,
+{
+  let until = tz.timestamp(vacation.until, 0).format("%F");
+  match &vacation.tag {
+    None => format!("假期模式中，闹钟将于 {} 恢复。", until),
+    Some(tag) => format!("假期模式中，#{} 闹钟将于 {} 恢复。", tag, until),
+  }
+}
+
+pub fn fmt_countdown(countdown: &Countdown, now: i64) -> String {
+  let remaining = format_duration((countdown.ends_at - now).max(0));
+  let title = match countdown.title.as_str() {
+    "" => String::default(),
+    title => format!(" {}", title),
+  };
+  match &countdown.pomodoro {
+    None => format!("[{}]{} 剩余 {}", countdown.id, title, remaining),
+    Some(pomodoro) => format!(
+      "[{}] 番茄钟 第 {}/{} 轮{}，剩余 {}",
+      countdown.id,
+      pomodoro.round,
+      pomodoro.rounds,
+      match pomodoro.is_resting {
+        true => "休息",
+        false => "专注",
+      },
+      remaining
+    ),
+  }
+}
+
+pub fn fmt_pomodoro_summary(countdown: &Countdown, now: i64) -> String {
+  let pomodoro = match &countdown.pomodoro {
+    None => return String::default(),
+    Some(pomodoro) => pomodoro,
+  };
+  let completed = match pomodoro.is_resting || countdown.ends_at <= now {
+    true => pomodoro.round,
+    false => pomodoro.round - 1,
+  };
+  format!(
+    "完成 {}/{} 轮，共专注 {}。",
+    completed,
+    pomodoro.rounds,
+    format_duration(countdown.focused(now))
+  )
+}
+
 pub fn f_about_message(f: &mut RTDFormattedTextBuilder) {
   let mut text = String::from("是\u{1f980}女仆。");
   let url_text = "点击查看源代码。";
@@ -91,17 +460,27 @@ pub fn f_list_alarms<'a, Z>(
   alarms: &Vec<Alarm>,
   tz: Z,
   chat_id: i64,
+  tag: Option<&str>,
+  vacation: Option<&Vacation>,
+  clock: &dyn Clock,
 ) where
   Z: TimeZone + 'static,
+  Z::Offset: Display,
 {
   let mut text = String::default();
   let mut entities: Vec<TextEntity> = vec![];
   let mut have_expired = false;
-  let now = tz.from_utc_datetime(&chrono::Local::now().naive_utc());
+  let mut have_broken = false;
+  let now = clock.now().with_timezone(&tz);
   for (i, alarm) in alarms.iter().enumerate() {
     if chat_id < 0 && alarm.chat_id != chat_id {
       continue;
     }
+    if let Some(tag) = tag {
+      if !alarm.has_tag(tag) {
+        continue;
+      }
+    }
     let num = format!("[{}]", i);
     let bold = TextEntityTypeBold::builder().build();
     let bold_entity = TextEntity::builder()
@@ -111,10 +490,13 @@ pub fn f_list_alarms<'a, Z>(
       .build();
     text += &format!("{}  ", num);
     entities.push(bold_entity);
-    if alarm.is_informing != 0 {
+    if alarm.is_broken() {
+      text += "#已损坏  ";
+      have_broken = true;
+    } else if alarm.ring.is_ringing() {
       text += "#进行中  ";
     } else {
-      let next_alarm = get_next_schedule(&alarm.cron, &now);
+      let next_alarm = get_next_schedule(alarm, &now);
       if !next_alarm.has_schedule() {
         text += "#已过期  ";
         have_expired = true;
@@ -123,13 +505,43 @@ pub fn f_list_alarms<'a, Z>(
     if alarm.is_disabled {
       text += "#已禁用  ";
     }
+    if let Some(vacation) = vacation {
+      if vacation.suspends(alarm) {
+        text += "#假期中  ";
+      }
+    }
     if alarm.title != "" {
       text += &format!("{}  ", alarm.title);
     }
+    let title_tags = parse_tags(&alarm.title);
+    for tag in alarm.tags().iter().filter(|tag| !title_tags.contains(tag)) {
+      text += &format!("#{}  ", tag);
+    }
     if alarm.is_strict {
       text += "#严格模式  ";
+      if alarm.challenge_kind != ChallengeKind::default()
+        || alarm.difficulty != Difficulty::default()
+      {
+        text += &format!(
+          "#{}{}  ",
+          fmt_challenge_kind(alarm.challenge_kind),
+          fmt_difficulty(alarm.difficulty)
+        );
+      }
     }
-    let cron = &alarm.cron[2..]; // remove zero for 'second'
+    if let Some(follow_up) = alarm.follow_up {
+      text += &format!(
+        "#{}后确认清醒  ",
+        format_duration(follow_up.after).replace(' ', "")
+      );
+    }
+    if alarm.heads_up > 0 {
+      text += &format!(
+        "#提前{}提醒  ",
+        format_duration(alarm.heads_up).replace(' ', "")
+      );
+    }
+    let cron = alarm.cron.get(2..).unwrap_or(&alarm.cron); // remove zero for 'second'
     let code = TextEntityTypeCode::builder().build();
     let code_entity = TextEntity::builder()
       .type_(TextEntityType::Code(code))
@@ -140,67 +552,69 @@ pub fn f_list_alarms<'a, Z>(
     entities.push(code_entity);
     text += "\n";
   }
+  if let Some(vacation) = vacation {
+    if !text.is_empty() {
+      text += &format!("\n{}", fmt_vacation(vacation, tz));
+    }
+  }
   if have_expired {
     text += "\nTip：使用命令 #purge 清除所有已过期的闹钟。"
   }
+  if have_broken {
+    text += "\nTip：已损坏的闹钟不会响，使用命令 #disalarm <编号> 移除它。"
+  }
   if text == "" {
-    text += "还一个闹钟都没有呢。";
-    if alarms.len() > 0 {
-      text += "回私聊中试试吧。"
+    match tag {
+      Some(tag) => text += &format!("没有带 #{} 标签的闹钟。", tag),
+      None => {
+        text += "还一个闹钟都没有呢。";
+        if alarms.len() > 0 {
+          text += "回私聊中试试吧。"
+        }
+      }
     }
   }
   f.text(text);
   f.entities(entities);
 }
 
-pub fn generate_strict_challenge() -> (String, String, String) {
-  let mut rng = rand::thread_rng();
-  let mut challenge = String::default();
-  let map_i = rng.gen_range(0, ANSWER_MAP.len());
-  let mut answer = String::default();
-  for _ in 0..30 {
-    let number = rng.gen_range(0, 11);
-    let number_string = number.to_string();
-    let num_str = match number_string.as_str() {
-      "10" => "X",
-      s => s,
-    };
-    challenge += num_str;
-    let char_vec: Vec<char> = ANSWER_MAP[map_i].chars().collect();
-    let answer_string = char_vec[number].to_string();
-    answer += &answer_string;
+pub fn f_strict_challenge(f: &mut RTDFormattedTextBuilder, challenge: &Challenge) {
+  if challenge.prompt.is_empty() {
+    f.text(&challenge.instruction);
+    return;
   }
-  return (challenge, answer, String::from(ANSWER_MAP[map_i]));
-}
-
-pub fn f_strict_challenge<T>(f: &mut RTDFormattedTextBuilder, challenge: T, challenge_map: T)
-where
-  T: AsRef<str>,
-{
-  let mut text = format!(
-    "请用汉字“{}”输入下面的数字以关闭闹钟：\n",
-    challenge_map.as_ref()
-  );
+  let mut text = format!("{}\n", challenge.instruction);
   let mut entities: Vec<TextEntity> = vec![];
   let code = TextEntityTypeCode::builder().build();
   let code_entity = TextEntity::builder()
     .type_(TextEntityType::Code(code))
     .offset(text.encode_utf16().count().try_into().unwrap())
-    .length(
-      challenge
-        .as_ref()
-        .encode_utf16()
-        .count()
-        .try_into()
-        .unwrap(),
-    )
+    .length(challenge.prompt.encode_utf16().count().try_into().unwrap())
     .build();
-  text += challenge.as_ref();
+  text += &challenge.prompt;
   entities.push(code_entity);
   f.text(text);
   f.entities(entities);
 }
 
+pub fn fmt_challenge_kind(kind: ChallengeKind) -> &'static str {
+  match kind {
+    ChallengeKind::Numerals => "数字转写",
+    ChallengeKind::Arithmetic => "算术题",
+    ChallengeKind::Sentence => "抄写句子",
+    ChallengeKind::Ordering => "数字排序",
+    ChallengeKind::Location => "离开床铺",
+  }
+}
+
+pub fn fmt_difficulty(difficulty: Difficulty) -> &'static str {
+  match difficulty {
+    Difficulty::Easy => "简单",
+    Difficulty::Normal => "普通",
+    Difficulty::Hard => "困难",
+  }
+}
+
 pub fn f_help_alarm<T>(f: &mut RTDFormattedTextBuilder, name: T, user_id: i64, is_discard: bool)
 where
   T: AsRef<str>,
@@ -1,13 +1,18 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::{env, io, sync::Arc, thread, time};
 extern crate uname;
-use crate::{alarm::*, cmd::*, cron::*, fmt::*, store::*};
+use crate::{
+  alarm::*, bedtime::*, challenge::*, client::*, clock::*, cmd::*, countdown::*, cron::*, fmt::*,
+  render::*, ring::*, scheduler::*, shared::*, stats::*, store::*,
+};
 use chrono::offset::TimeZone;
 use chrono_tz::Tz;
+use rand::prelude::*;
 use rtdlib::{tdjson::Tdlib, types::*};
 
-pub fn initialize_app() -> (Arc<Tdlib>, Arc<Store>) {
-  let tdlib = Arc::new(Tdlib::new());
+pub fn initialize_app() -> (Arc<Client>, Arc<Store>, Arc<dyn Clock>, Arc<Scheduler>) {
+  let tdlib = Arc::new(Client::new());
   Tdlib::set_log_verbosity_level(2).unwrap();
   let set_online = SetOption::builder()
     .name("online")
@@ -20,11 +25,27 @@ pub fn initialize_app() -> (Arc<Tdlib>, Arc<Store>) {
     "{}/store.json",
     env::var("DATA_PATH").expect("Unknown env DATA_PATH")
   )));
-  return (tdlib, store);
+  let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+  let scheduler = Arc::new(Scheduler::new());
+  return (tdlib, store, clock, scheduler);
 }
 
-pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle<()> {
+pub fn start_handler(
+  tdlib: Arc<Client>,
+  store: Arc<Store>,
+  clock: Arc<dyn Clock>,
+  scheduler: Arc<Scheduler>,
+) -> thread::JoinHandle<()> {
   let mut user_name = String::default();
+  // Users who asked to register their home with the next location they
+  // share, and the distance they chose.
+  let mut awaiting_home: HashMap<i64, f64> = HashMap::new();
+  // The live location each private chat last shared, the only message whose
+  // edits count for location challenges.
+  let mut live_locations: HashMap<i64, i64> = HashMap::new();
+  // Lowercase usernames of the users TDLib told about, so that a contact
+  // named by username can be recognized.
+  let mut usernames: HashMap<i64, String> = HashMap::new();
   let phone_number = env::var("PHONE").expect("Unknown env PHONE");
   let phone_number = if phone_number.starts_with("+") {
     phone_number[1..].to_string()
@@ -37,29 +58,53 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
       continue;
     }
     let json = json.unwrap();
+    if let Some(response) = tdlib.take_response(json.as_str()) {
+      if let Response::Done(
+        Request::Call {
+          user_id,
+          alarm_id: Some(alarm_id),
+        },
+        value,
+      ) = &response
+      {
+        if let Some(call_id) = value.get("id").and_then(|id| id.as_i64()) {
+          let is_bound = {
+            let state = store.state();
+            bind_call(&state, &*clock, *user_id, *alarm_id, call_id)
+          };
+          if is_bound {
+            store.save().expect("Failed to save state");
+          }
+        }
+      }
+      if let Response::Failed { request, error } = &response {
+        let user_id = match request {
+          Some(request) => {
+            let state = store.state();
+            handle_error(&tdlib, &state, &*clock, *request, error)
+          }
+          None => {
+            println!(
+              "[{}] Request failed: {} {}",
+              clock.timestamp(),
+              error.code(),
+              error.message()
+            );
+            None
+          }
+        };
+        if let Some(user_id) = user_id {
+          store.save().expect("Failed to save state");
+          scheduler.touch(user_id);
+        }
+      }
+      continue;
+    }
     let td_type = detect_td_type(json.as_str());
     if let None = td_type {
       eprintln!("data failed with json");
       continue;
     };
-    let unlock_user = |user_id: i64, sleeping_map: &mut HashMap<i64, RefCell<Vec<i64>>>| {
-      let user_sleeping = sleeping_map.get(&user_id);
-      if let None = user_sleeping {
-        return;
-      }
-      let user_sleeping = sleeping_map.get(&user_id).unwrap();
-      for chat_id in user_sleeping.borrow().iter() {
-        let req = SetChatMemberStatus::builder()
-          .chat_id(*chat_id)
-          .user_id(user_id)
-          .status(ChatMemberStatus::Member(
-            ChatMemberStatusMember::builder().build(),
-          ))
-          .build();
-        tdlib.send(&req.to_json().expect("Bad JSON"));
-      }
-      sleeping_map.insert(user_id, RefCell::new(vec![]));
-    };
     let td_type = td_type.unwrap();
     match td_type.as_str() {
       "updateAuthorizationState" => {
@@ -129,25 +174,59 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
       "updateUser" => {
         let update_user: UpdateUser = serde_json::from_str(json.as_str()).unwrap_or_default();
         let user = update_user.user();
+        let mut touched_groups = vec![];
         {
           let state = store.state();
           let mut users_map = state.users.borrow_mut();
           let mut alarms_map = state.alarms.borrow_mut();
           let mut timezone_map = state.timezone.borrow_mut();
           let mut sleeping_map = state.sleeping.borrow_mut();
+          let mut bedtime_map = state.bedtime.borrow_mut();
+          let mut vacation_map = state.vacation.borrow_mut();
+          let mut countdowns_map = state.countdowns.borrow_mut();
+          let mut quiet_hours_map = state.quiet_hours.borrow_mut();
+          let mut contacts_map = state.contacts.borrow_mut();
+          let mut incoming_call_map = state.incoming_call.borrow_mut();
+          let mut homes_map = state.homes.borrow_mut();
           match user.type_() {
             UserType::Regular(_) => {
               users_map.insert(user.id(), user.first_name().clone());
+              usernames.insert(user.id(), user.username().to_lowercase());
             }
             _ => {
               users_map.remove(&user.id());
               alarms_map.remove(&user.id());
               timezone_map.remove(&user.id());
               sleeping_map.remove(&user.id());
+              bedtime_map.remove(&user.id());
+              vacation_map.remove(&user.id());
+              countdowns_map.remove(&user.id());
+              quiet_hours_map.remove(&user.id());
+              contacts_map.remove(&user.id());
+              incoming_call_map.remove(&user.id());
+              homes_map.remove(&user.id());
+              for stats in state.group_stats.borrow_mut().values_mut() {
+                stats.forget(user.id());
+              }
+              for (chat_id, shared_alarms) in state.shared_alarms.borrow().iter() {
+                let mut shared_alarms = shared_alarms.borrow_mut();
+                let mut is_touched = false;
+                for shared in shared_alarms.iter_mut() {
+                  is_touched |= shared.unsubscribe(user.id());
+                }
+                if is_touched {
+                  shared_alarms.retain(|shared| !shared.subscribers.is_empty());
+                  touched_groups.push(*chat_id);
+                }
+              }
             }
           }
         }
         store.save().expect("Failed to save state");
+        scheduler.touch(user.id());
+        for chat_id in touched_groups {
+          scheduler.touch(chat_id);
+        }
       }
       "updateNewMessage" => {
         let update_new_message: UpdateNewMessage =
@@ -175,7 +254,12 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
             .input_message_content(msg)
             .reply_to_message_id(message.id())
             .build();
-          tdlib.send(&req.to_json().expect("Bad JSON"));
+          tdlib.request(
+            &req,
+            Request::Message {
+              chat_id: message.chat_id(),
+            },
+          );
         };
         let view_msg = || {
           let req = ViewMessages::builder()
@@ -198,7 +282,7 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                 view_msg();
               }
               let mut toggled = false;
-              let now = chrono::Local::now().timestamp();
+              let now = clock.timestamp();
               {
                 let state = store.state();
                 let alarms_map = state.alarms.borrow();
@@ -209,28 +293,83 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                 }
                 let user_alarms = user_alarms.unwrap();
                 let mut alarms = user_alarms.borrow_mut();
-                for alarm in alarms.iter_mut() {
-                  if alarm.is_strict && alarm.is_informing != 0 {
-                    if text == alarm.strict_challenge.as_str() {
-                      alarm.is_informing = 0;
+                let mut wrong_answered = None;
+                if message.chat_id() > 0 {
+                  for alarm in alarms.iter_mut() {
+                    if alarm.ring.confirm_awake() {
+                      toggled = true;
+                      println!("[{}] User is still awake after alarm {}", now, alarm);
+                    }
+                  }
+                  if toggled {
+                    reply_text_msg(build_plain_message("很好，今天也要元气满满！"));
+                  }
+                }
+                for (i, alarm) in alarms.iter_mut().enumerate() {
+                  let ring = alarm.ring.ring().cloned();
+                  if !alarm.is_strict {
+                    if message.chat_id() > 0 && alarm.ring.acknowledge() {
+                      toggled = true;
+                      reply_text_msg(if alarm.title.is_empty() {
+                        build_plain_message("闹钟已关闭。")
+                      } else {
+                        build_plain_message(format!("闹钟 {} 已关闭。", alarm.title))
+                      });
+                      unlock_user(&tdlib, message.sender_user_id(), &mut sleeping_map);
+                      println!("[{}] Fulfilled alarm {} due to replying", now, alarm);
+                      record_wake(&tdlib, &state, alarm, ring.as_ref(), false, now);
+                      start_follow_up(alarm, now);
+                      break;
+                    }
+                  } else {
+                    if alarm.ring.solve(text) {
                       toggled = true;
                       reply_text_msg(if alarm.title == "" {
                         build_plain_message(format!("闹钟已关闭。"))
                       } else {
                         build_plain_message(format!("闹钟 {} 已关闭。", alarm.title))
                       });
-                      unlock_user(message.sender_user_id(), &mut sleeping_map);
+                      unlock_user(&tdlib, message.sender_user_id(), &mut sleeping_map);
                       println!(
                         "[{}] Fulfilled alarm {} due to completing challenge",
                         now, alarm
                       );
+                      record_wake(&tdlib, &state, alarm, ring.as_ref(), false, now);
+                      start_follow_up(alarm, now);
                       break;
                     }
+                    if message.chat_id() > 0
+                      && wrong_answered.is_none()
+                      && alarm.ring.challenge().is_some()
+                    {
+                      wrong_answered = Some(i);
+                    }
+                  }
+                }
+                if let Some(i) = wrong_answered.filter(|_| !toggled) {
+                  toggled = true;
+                  let alarm = &mut alarms[i];
+                  let hint = alarm.ring.challenge().unwrap().hint(text);
+                  let wrong_answers = alarm.ring.fail_challenge(now);
+                  println!(
+                    "[{}] Wrong answer {} to the challenge of alarm {}",
+                    now, wrong_answers, alarm
+                  );
+                  if alarm.regenerate_after > 0 && wrong_answers % alarm.regenerate_after == 0 {
+                    reply_text_msg(build_plain_message(format!(
+                      "答案不对，{}已经错了 {} 次，换一道题吧。",
+                      hint, wrong_answers
+                    )));
+                    let challenge = issue_challenge(alarm, now);
+                    reply_text_msg(build_challenge_message(alarm, &challenge));
+                  } else {
+                    reply_text_msg(build_plain_message(format!("答案不对，{}", hint)));
                   }
                 }
               }
               if toggled {
                 store.save().expect("Failed to save state");
+                scheduler.touch(message.sender_user_id());
               }
               continue;
             }
@@ -254,8 +393,8 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
               };
               let alarm_args = {
                 match tz {
-                  Some(tz) => parse_alarm_args(cmd.arg(), &tz),
-                  None => parse_alarm_args(cmd.arg(), &chrono::Local),
+                  Some(tz) => parse_alarm_args(cmd.arg(), &tz, &*clock),
+                  None => parse_alarm_args(cmd.arg(), &chrono::Local, &*clock),
                 }
               };
               let to_send = match alarm_args {
@@ -274,25 +413,43 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                   if let None = user_alarms {
                     alarms_map.insert(message.sender_user_id(), RefCell::new(vec![]));
                   }
-                  let now_utc = chrono::Local::now().naive_utc();
+                  let now_utc = clock.now().naive_utc();
                   let mut user_alarms = alarms_map
                     .get(&message.sender_user_id())
                     .unwrap()
                     .borrow_mut();
-                  user_alarms.push(alarm);
                   let next_alarm = match tz {
                     Some(tz) => {
-                      get_next_schedule(cron_args.cron(), &tz.from_utc_datetime(&now_utc))
-                        .to_string()
+                      get_next_schedule(&alarm, &tz.from_utc_datetime(&now_utc)).to_string()
                     }
-                    None => get_next_schedule(
-                      cron_args.cron(),
-                      &chrono::Local.from_utc_datetime(&now_utc),
-                    )
-                    .to_string(),
+                    None => get_next_schedule(&alarm, &chrono::Local.from_utc_datetime(&now_utc))
+                      .to_string(),
                   };
+                  let quiet_warning =
+                    match state.quiet_hours.borrow().get(&message.sender_user_id()) {
+                      Some(quiet_hours) if !is_strict => {
+                        let in_quiet_hours = match tz {
+                          Some(tz) => rings_in_quiet_hours(
+                            &alarm,
+                            quiet_hours,
+                            &tz.from_utc_datetime(&now_utc),
+                          ),
+                          None => rings_in_quiet_hours(
+                            &alarm,
+                            quiet_hours,
+                            &chrono::Local.from_utc_datetime(&now_utc),
+                          ),
+                        };
+                        match in_quiet_hours {
+                          true => format!("\n注意：{}", fmt_quiet_hours(quiet_hours)),
+                          false => String::default(),
+                        }
+                      }
+                      _ => String::default(),
+                    };
+                  state.add_alarm(&mut user_alarms, alarm);
                   let next_alarm = match next_alarm {
-                    Some(next_alarm) => format!("下次闹钟时间：{}", next_alarm),
+                    Some(next_alarm) => format!("下次闹钟时间：{}{}", next_alarm, quiet_warning),
                     None => format!("但是它看起来并不会响。"),
                   };
                   Ok(match cron_args.title() {
@@ -304,6 +461,7 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
               match to_send {
                 Ok(to_send) => {
                   store.save().expect("Failed to save state");
+                  scheduler.touch(message.sender_user_id());
                   reply_text_msg(build_plain_message(to_send));
                 }
                 Err(_) => {
@@ -311,6 +469,114 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                 }
               }
             };
+            let handle_timer = |is_calling: bool| {
+              let args = parse_command_msg(cmd.arg());
+              let to_send = match parse_duration(args.cmd()) {
+                Ok(duration) if duration > 0 && duration <= 86400 => {
+                  let now = clock.timestamp();
+                  let state = store.state();
+                  let mut countdowns_map = state.countdowns.borrow_mut();
+                  let mut countdowns = countdowns_map
+                    .entry(message.sender_user_id())
+                    .or_insert_with(|| RefCell::new(vec![]))
+                    .borrow_mut();
+                  let id = next_countdown_id(&countdowns);
+                  let countdown =
+                    Countdown::timer(id, message.chat_id(), args.arg(), duration, is_calling, now);
+                  println!(
+                    "[{}] Started countdown {} of user {} ending at {}",
+                    now,
+                    id,
+                    message.sender_user_id(),
+                    countdown.ends_at
+                  );
+                  countdowns.push(countdown);
+                  Ok(format!(
+                    "计时 [{}] 已开始，{} 后提醒你。",
+                    id,
+                    format_duration(duration)
+                  ))
+                }
+                _ => Err(()),
+              };
+              match to_send {
+                Ok(to_send) => {
+                  store.save().expect("Failed to save state");
+                  scheduler.touch(message.sender_user_id());
+                  reply_text_msg(build_plain_message(to_send));
+                }
+                Err(_) => {
+                  reply_text_msg(build_fmt_message(|f| {
+                    f_bad_arguments(f, "时长格式有误，例如 #timer 25m #focus ，最长 24 小时。")
+                  }));
+                }
+              }
+            };
+            let handle_pomodoro = |is_calling: bool| {
+              let args: Vec<&str> = cmd.arg().split_whitespace().collect();
+              let work = match args.first() {
+                None => Ok(25 * 60),
+                Some(arg) => parse_duration(arg),
+              };
+              let rest = match args.get(1) {
+                None => Ok(5 * 60),
+                Some(arg) => parse_duration(arg),
+              };
+              let rounds = match args.get(2) {
+                None => Ok(4),
+                Some(arg) => arg.parse::<i64>(),
+              };
+              let to_send = match (work, rest, rounds) {
+                (Ok(work), Ok(rest), Ok(rounds))
+                  if work > 0
+                    && work <= 14400
+                    && rest > 0
+                    && rest <= 3600
+                    && rounds > 0
+                    && rounds <= 12
+                    && args.len() <= 3 =>
+                {
+                  let now = clock.timestamp();
+                  let state = store.state();
+                  let mut countdowns_map = state.countdowns.borrow_mut();
+                  let mut countdowns = countdowns_map
+                    .entry(message.sender_user_id())
+                    .or_insert_with(|| RefCell::new(vec![]))
+                    .borrow_mut();
+                  let id = next_countdown_id(&countdowns);
+                  let countdown =
+                    Countdown::pomodoro(id, message.chat_id(), work, rest, rounds, is_calling, now);
+                  println!(
+                    "[{}] Started pomodoro {} of user {} with {} rounds",
+                    now,
+                    id,
+                    message.sender_user_id(),
+                    rounds
+                  );
+                  countdowns.push(countdown);
+                  Ok(format!(
+                    "番茄钟 [{}] 已开始：专注 {}，休息 {}，共 {} 轮。",
+                    id,
+                    format_duration(work),
+                    format_duration(rest),
+                    rounds
+                  ))
+                }
+                _ => Err(()),
+              };
+              match to_send {
+                Ok(to_send) => {
+                  store.save().expect("Failed to save state");
+                  scheduler.touch(message.sender_user_id());
+                  reply_text_msg(build_plain_message(to_send));
+                }
+                Err(_) => {
+                  reply_text_msg(build_fmt_message(|f| {
+                    f_bad_arguments(f, "参数有误，例如 #pomodoro 25m 5m 4 。")
+                  }));
+                }
+              }
+            };
             match cmd.cmd() {
               "#about" => {
                 reply_text_msg(build_fmt_message(f_about_message));
@@ -325,7 +591,11 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                   let tz = timezone_map.get(&message.sender_user_id());
                   let current_tz_str = match tz {
                     Some(tz) => tz.clone(),
-                    None => chrono::Local::now().format("%Z").to_string(),
+                    None => clock
+                      .now()
+                      .with_timezone(&chrono::Local)
+                      .format("%Z")
+                      .to_string(),
                   };
                   reply_text_msg(build_plain_message(format!("当前时区：{}", current_tz_str)));
                   continue;
@@ -341,6 +611,7 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                   }
                 };
                 store.save().expect("Failed to save state");
+                scheduler.touch(message.sender_user_id());
                 reply_text_msg(to_send);
               }
               "#alarm" => {
@@ -360,19 +631,34 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                   }
                   None => None,
                 };
+                let tag = parse_tags(cmd.arg());
+                let tag = tag.first().map(|tag| tag.as_str());
                 let alarms_map = state.alarms.borrow();
+                let vacation_map = state.vacation.borrow();
+                let vacation = vacation_map.get(&message.sender_user_id());
                 let user_alarms = alarms_map.get(&message.sender_user_id());
                 let to_send = match user_alarms {
                   None => {
                     build_fmt_message(|f| f_bad_arguments(f, "还没有设置过闹钟呢，去设置一些吧。"))
                   }
                   Some(alarms) => build_fmt_message(|f| match tz {
-                    Some(tz) => f_list_alarms(f, &alarms.borrow(), tz, message.chat_id()),
+                    Some(tz) => f_list_alarms(
+                      f,
+                      &alarms.borrow(),
+                      tz,
+                      message.chat_id(),
+                      tag,
+                      vacation,
+                      &*clock,
+                    ),
                     None => f_list_alarms(
                       f,
                       &alarms.borrow(),
                       chrono::Local.clone(),
                       message.chat_id(),
+                      tag,
+                      vacation,
+                      &*clock,
                     ),
                   }),
                 };
@@ -381,10 +667,14 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
               "#disalarm" => {
                 if cmd.arg() == "" {
                   let to_send = {
-                    let now = chrono::Local::now().timestamp();
+                    let now = clock.timestamp();
                     let state = store.state();
                     let alarms_map = state.alarms.borrow();
                     let timezone_map = state.timezone.borrow();
+                    let vacation_map = state.vacation.borrow();
+                    let vacation = vacation_map.get(&message.sender_user_id());
+                    let quiet_hours_map = state.quiet_hours.borrow();
+                    let quiet_hours = quiet_hours_map.get(&message.sender_user_id());
                     let user_alarms = alarms_map.get(&message.sender_user_id());
                     match user_alarms {
                       None => build_fmt_message(|f| {
@@ -410,25 +700,26 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                             }
                             let a = a.unwrap();
                             let s = s.unwrap();
-                            if a.is_pending {
+                            if a.ring.is_calling() {
                               return build_plain_message(
                                 "你不能移除正在响铃的闹钟，请先关闭闹钟。",
                               );
                             }
-                            if a.is_informing != 0 && a.is_strict {
+                            if a.ring.is_ringing() && a.is_strict {
                               return build_plain_message(
                                 "你不能移除正在进行的闹钟，请先关闭闹钟。",
                               );
                             }
-                            if a.is_informing != 0 {
-                              a.is_informing = 0;
+                            let ring = a.ring.ring().cloned();
+                            if a.ring.give_up() {
+                              record_wake(&tdlib, &state, a, ring.as_ref(), true, now);
                               return if a.title == "" {
                                 build_plain_message("已关闭正在进行的闹钟。")
                               } else {
                                 build_plain_message(format!("已关闭正在进行的闹钟 {}。", a.title))
                               };
                             }
-                            if t >= now && t < now + 3600 {
+                            if t >= now && (t < now + 3600 || a.heads_up_sent == t) {
                               a.is_onceoff = true;
                               return build_plain_message(if a.title == "" {
                                 format!("已取消预定于 {} 的闹钟。", s)
@@ -451,6 +742,9 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                               &mut *alarms,
                               tz.parse::<Tz>().unwrap(),
                               message.chat_id(),
+                              vacation,
+                              quiet_hours,
+                              &*clock,
                             );
                             disalarm_if_in_an_hour(
                               next_alarm.schedule().to_timestamp(),
@@ -463,6 +757,9 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                               &mut *alarms,
                               chrono::Local.clone(),
                               message.chat_id(),
+                              vacation,
+                              quiet_hours,
+                              &*clock,
                             );
                             disalarm_if_in_an_hour(
                               next_alarm.schedule().to_timestamp(),
@@ -475,35 +772,105 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                     }
                   };
                   store.save().expect("Failed to save state");
+                  scheduler.touch(message.sender_user_id());
                   reply_text_msg(to_send);
                   continue;
                 }
-                reply_text_msg(with_alarm_id(
+                let given_up = RefCell::new(vec![]);
+                let removed = RefCell::new(vec![]);
+                reply_text_msg(with_alarm_id_and_arg(
                   &store,
+                  &scheduler,
                   message.sender_user_id(),
                   &cmd,
-                  |alarms, id| {
-                    if alarms[id].is_strict && alarms[id].is_informing != 0 {
+                  |alarms, id, arg| {
+                    if arg == "once" {
+                      let alarm = &mut alarms[id];
+                      return if alarm.ring.is_ringing() {
+                        build_plain_message("这个闹钟正在进行，请先关闭闹钟。")
+                      } else if alarm.is_disabled {
+                        build_plain_message("这个闹钟已禁用，不会响。")
+                      } else {
+                        alarm.is_onceoff = true;
+                        build_plain_message(match alarm.title.as_str() {
+                          "" => String::from("已取消闹钟的下一次响铃。"),
+                          title => format!("已取消闹钟 {} 的下一次响铃。", title),
+                        })
+                      };
+                    }
+                    if !arg.is_empty() {
+                      build_fmt_message(|f| {
+                        f_bad_arguments(
+                          f,
+                          "参数有误，例如 #disalarm 0 移除闹钟，#disalarm 0 once 只取消下一次。",
+                        )
+                      })
+                    } else if alarms[id].is_strict && alarms[id].ring.is_ringing() {
                       build_plain_message("你不能移除正在进行的闹钟，请先关闭闹钟。")
                     } else {
-                      alarms.remove(id);
+                      let alarm = alarms.remove(id);
+                      if let Some(ring) = alarm.ring.ring().filter(|_| alarm.ring.is_ringing()) {
+                        given_up.borrow_mut().push((alarm.clone(), ring.clone()));
+                      }
+                      removed.borrow_mut().push(alarm);
                       build_plain_message("闹钟已移除。")
                     }
                   },
                 ));
+                record_given_up(&tdlib, &store, given_up.into_inner(), clock.timestamp());
+                leave_removed_shared(&store, &scheduler, removed.into_inner());
               }
               "#disable" => {
+                let given_up = RefCell::new(vec![]);
+                if cmd.arg().starts_with('#') {
+                  let tags = parse_tags(cmd.arg());
+                  let tag = tags.first().map(|tag| tag.as_str()).unwrap_or_default();
+                  let (matched, changed) =
+                    with_alarm_tag(&store, &scheduler, message.sender_user_id(), tag, |alarm| {
+                      if alarm.is_disabled || (alarm.is_strict && alarm.ring.is_ringing()) {
+                        return false;
+                      }
+                      let ring = alarm.ring.ring().cloned();
+                      if alarm.ring.give_up() {
+                        given_up
+                          .borrow_mut()
+                          .extend(ring.map(|ring| (alarm.clone(), ring)));
+                      }
+                      alarm.is_disabled = true;
+                      true
+                    });
+                  reply_text_msg(match (matched, changed) {
+                    (0, _) => build_fmt_message(|f| f_bad_arguments(f, "没有带这个标签的闹钟。")),
+                    (m, c) if m == c => {
+                      build_plain_message(format!("已禁用 {} 个 #{} 闹钟。", c, tag))
+                    }
+                    (m, c) => build_plain_message(format!(
+                      "已禁用 {} 个 #{} 闹钟，其余 {} 个已经是禁用状态或正在进行。",
+                      c,
+                      tag,
+                      m - c
+                    )),
+                  });
+                  record_given_up(&tdlib, &store, given_up.into_inner(), clock.timestamp());
+                  continue;
+                }
                 reply_text_msg(with_alarm_id(
                   &store,
+                  &scheduler,
                   message.sender_user_id(),
                   &cmd,
                   |alarms, id| {
-                    if alarms[id].is_strict && alarms[id].is_informing != 0 {
+                    if alarms[id].is_strict && alarms[id].ring.is_ringing() {
                       build_plain_message("你不能禁用正在进行的闹钟，请先关闭闹钟。")
                     } else if alarms[id].is_disabled {
                       build_plain_message("闹钟已经是禁用状态。")
                     } else {
-                      alarms[id].is_informing = 0;
+                      let ring = alarms[id].ring.ring().cloned();
+                      if alarms[id].ring.give_up() {
+                        given_up
+                          .borrow_mut()
+                          .extend(ring.map(|ring| (alarms[id].clone(), ring)));
+                      }
                       alarms[id].is_disabled = true;
                       if alarms[id].title == "" {
                         build_plain_message("闹钟已禁用。")
@@ -513,10 +880,37 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                     }
                   },
                 ));
+                record_given_up(&tdlib, &store, given_up.into_inner(), clock.timestamp());
               }
               "#enable" => {
+                if cmd.arg().starts_with('#') {
+                  let tags = parse_tags(cmd.arg());
+                  let tag = tags.first().map(|tag| tag.as_str()).unwrap_or_default();
+                  let (matched, changed) =
+                    with_alarm_tag(&store, &scheduler, message.sender_user_id(), tag, |alarm| {
+                      if !alarm.is_disabled {
+                        return false;
+                      }
+                      alarm.is_disabled = false;
+                      true
+                    });
+                  reply_text_msg(match (matched, changed) {
+                    (0, _) => build_fmt_message(|f| f_bad_arguments(f, "没有带这个标签的闹钟。")),
+                    (m, c) if m == c => {
+                      build_plain_message(format!("已启用 {} 个 #{} 闹钟。", c, tag))
+                    }
+                    (m, c) => build_plain_message(format!(
+                      "已启用 {} 个 #{} 闹钟，其余 {} 个已经是启用状态。",
+                      c,
+                      tag,
+                      m - c
+                    )),
+                  });
+                  continue;
+                }
                 reply_text_msg(with_alarm_id(
                   &store,
+                  &scheduler,
                   message.sender_user_id(),
                   &cmd,
                   |alarms, id| {
@@ -533,13 +927,196 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                   },
                 ));
               }
+              "#tag" => {
+                reply_text_msg(with_alarm_id_and_arg(
+                  &store,
+                  &scheduler,
+                  message.sender_user_id(),
+                  &cmd,
+                  |alarms, id, arg| {
+                    let tags = parse_tags(arg);
+                    let alarm_text = match alarms[id].title.as_str() {
+                      "" => format!("[{}]", id),
+                      title => format!("[{}] {}", id, title),
+                    };
+                    let to_send = if tags.is_empty() {
+                      build_plain_message(format!("已清除闹钟 {} 的标签。", alarm_text))
+                    } else {
+                      build_plain_message(format!(
+                        "已将闹钟 {} 的标签设置为 #{}。",
+                        alarm_text,
+                        tags.join(" #")
+                      ))
+                    };
+                    alarms[id].tags = Some(tags);
+                    to_send
+                  },
+                ));
+              }
+              "#headsup" => {
+                reply_text_msg(with_alarm_id_and_arg(
+                  &store,
+                  &scheduler,
+                  message.sender_user_id(),
+                  &cmd,
+                  |alarms, id, arg| {
+                    let alarm_text = match alarms[id].title.as_str() {
+                      "" => format!("[{}]", id),
+                      title => format!("[{}] {}", id, title),
+                    };
+                    match (arg, parse_duration(arg)) {
+                      ("off", _) => {
+                        alarms[id].heads_up = 0;
+                        build_plain_message(format!("已关闭闹钟 {} 的提前提醒。", alarm_text))
+                      }
+                      (_, Ok(lead)) if lead > 0 && lead <= 86400 => {
+                        alarms[id].heads_up = lead;
+                        build_plain_message(format!(
+                          "闹钟 {} 将在每次响铃前 {}提醒你。",
+                          alarm_text,
+                          format_duration(lead)
+                        ))
+                      }
+                      _ => build_fmt_message(|f| {
+                        f_bad_arguments(f, "提前时间格式有误，例如 #headsup 0 10m ，最长 24 小时。")
+                      }),
+                    }
+                  },
+                ));
+              }
+              "#fallback" => {
+                reply_text_msg(with_alarm_id_and_arg(
+                  &store,
+                  &scheduler,
+                  message.sender_user_id(),
+                  &cmd,
+                  |alarms, id, arg| {
+                    let alarm_text = match alarms[id].title.as_str() {
+                      "" => format!("[{}]", id),
+                      title => format!("[{}] {}", id, title),
+                    };
+                    let args = parse_command_msg(arg);
+                    let chain: Result<Vec<RingChannel>, _> =
+                      args.arg().split_whitespace().map(|c| c.parse()).collect();
+                    match (args.cmd(), args.cmd().parse::<i64>(), chain) {
+                      ("", _, _) => build_plain_message(match &alarms[id].fallback {
+                        None => format!("闹钟 {} 只会打电话。", alarm_text),
+                        Some(fallback) => format!("闹钟 {} {}", alarm_text, fmt_fallback(fallback)),
+                      }),
+                      ("off", _, _) => {
+                        alarms[id].fallback = None;
+                        build_plain_message(format!("闹钟 {} 只会打电话。", alarm_text))
+                      }
+                      (_, Ok(after), Ok(chain)) if after > 0 && after <= 10 => {
+                        let mut fallback = Fallback::new(after);
+                        if !chain.is_empty() {
+                          fallback.chain = chain;
+                        }
+                        let to_send = format!("闹钟 {} {}", alarm_text, fmt_fallback(&fallback));
+                        alarms[id].fallback = Some(fallback);
+                        build_plain_message(to_send)
+                      }
+                      _ => build_fmt_message(|f| {
+                        f_bad_arguments(f, "参数有误，例如 #fallback 0 2 message pin voice 。")
+                      }),
+                    }
+                  },
+                ));
+              }
+              "#followup" => {
+                reply_text_msg(with_alarm_id_and_arg(
+                  &store,
+                  &scheduler,
+                  message.sender_user_id(),
+                  &cmd,
+                  |alarms, id, arg| {
+                    let alarm_text = match alarms[id].title.as_str() {
+                      "" => format!("[{}]", id),
+                      title => format!("[{}] {}", id, title),
+                    };
+                    let args: Vec<&str> = arg.split_whitespace().collect();
+                    let follow_up = match args.as_slice() {
+                      ["off"] => Ok(None),
+                      [after, window] => match (parse_duration(after), parse_duration(window)) {
+                        (Ok(after), Ok(window))
+                          if (60..=7200).contains(&after) && (60..=3600).contains(&window) =>
+                        {
+                          Ok(Some(FollowUp { after, window }))
+                        }
+                        _ => Err(()),
+                      },
+                      _ => Err(()),
+                    };
+                    match follow_up {
+                      Ok(None) => {
+                        alarms[id].follow_up = None;
+                        if let RingState::FollowingUp(_) = alarms[id].ring {
+                          alarms[id].ring.give_up();
+                        }
+                        build_plain_message(format!("已取消闹钟 {} 的清醒确认。", alarm_text))
+                      }
+                      Ok(Some(follow_up)) => {
+                        alarms[id].follow_up = Some(follow_up);
+                        build_plain_message(format!(
+                          "闹钟 {} 关闭 {}后会确认你是否还醒着，{}内没有回复就重新响起。",
+                          alarm_text,
+                          format_duration(follow_up.after),
+                          format_duration(follow_up.window)
+                        ))
+                      }
+                      Err(_) => build_fmt_message(|f| {
+                        f_bad_arguments(
+                          f,
+                          "参数有误，例如 #followup 0 15m 5m 或 #followup 0 off ，等待最长 2 小时，回复时限最长 1 小时。",
+                        )
+                      }),
+                    }
+                  },
+                ));
+              }
+              "#mincall" => {
+                reply_text_msg(with_alarm_id_and_arg(
+                  &store,
+                  &scheduler,
+                  message.sender_user_id(),
+                  &cmd,
+                  |alarms, id, arg| {
+                    let alarm_text = match alarms[id].title.as_str() {
+                      "" => format!("[{}]", id),
+                      title => format!("[{}] {}", id, title),
+                    };
+                    let duration = match arg {
+                      "off" => Ok(0),
+                      arg => parse_duration(arg),
+                    };
+                    match duration {
+                      Ok(0) => {
+                        alarms[id].min_call_duration = 0;
+                        build_plain_message(format!("闹钟 {} 接通电话即可关闭。", alarm_text))
+                      }
+                      Ok(duration) if duration <= 600 => {
+                        alarms[id].min_call_duration = duration;
+                        build_plain_message(format!(
+                          "闹钟 {} 需要保持通话 {}才算接听，提前挂断会再打过来。",
+                          alarm_text,
+                          format_duration(duration)
+                        ))
+                      }
+                      _ => build_fmt_message(|f| {
+                        f_bad_arguments(f, "通话时长格式有误，例如 #mincall 0 30s ，最长 10 分钟。")
+                      }),
+                    }
+                  },
+                ));
+              }
               "#strict" => {
                 reply_text_msg(with_alarm_id(
                   &store,
+                  &scheduler,
                   message.sender_user_id(),
                   &cmd,
                   |alarms, id| {
-                    if alarms[id].is_informing != 0 {
+                    if alarms[id].ring.is_ringing() {
                       build_plain_message("你不能对正在进行的闹钟使用此命令。")
                     } else {
                       alarms[id].is_strict = !alarms[id].is_strict;
@@ -555,145 +1132,1271 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
                   },
                 ));
               }
-              "#next" => {
-                let state = store.state();
-                let alarms_map = state.alarms.borrow();
-                let timezone_map = state.timezone.borrow();
-                let user_alarms = alarms_map.get(&message.sender_user_id());
-                if let None = user_alarms {
-                  continue;
-                }
-                let alarms = user_alarms.unwrap().borrow();
-                let tz = timezone_map.get(&message.sender_user_id());
-                let (time_str, alarm_title) = match tz {
-                  Some(tz) => {
-                    let next_alarm =
-                      get_recent_schedule(&alarms, tz.parse::<Tz>().unwrap(), message.chat_id());
-                    (next_alarm.schedule().to_string(), next_alarm.alarm_title())
-                  }
-                  None => {
-                    let next_alarm =
-                      get_recent_schedule(&alarms, chrono::Local.clone(), message.chat_id());
-                    (next_alarm.schedule().to_string(), next_alarm.alarm_title())
-                  }
+              "#challenge" => {
+                let has_home = {
+                  let state = store.state();
+                  let has_home = state.homes.borrow().contains_key(&message.sender_user_id());
+                  has_home
                 };
-                let to_send = match time_str {
-                  Some(time_str) => {
-                    build_plain_message(format!("下次闹钟时间：{} {}", time_str, alarm_title))
-                  }
-                  None => {
-                    if message.chat_id() < 0 {
-                      build_plain_message(format!("这群看不到更多要响的闹钟了，不如回私聊试试看？"))
-                    } else {
-                      build_fmt_message(|f| f_bad_arguments(f, "没有要响的闹钟了，去设置一些吧。"))
+                reply_text_msg(with_alarm_id_and_arg(
+                  &store,
+                  &scheduler,
+                  message.sender_user_id(),
+                  &cmd,
+                  |alarms, id, arg| {
+                    let alarm_text = match alarms[id].title.as_str() {
+                      "" => format!("[{}]", id),
+                      title => format!("[{}] {}", id, title),
+                    };
+                    let mut args = arg.split_whitespace();
+                    let kind = match args.next() {
+                      None => Ok(alarms[id].challenge_kind),
+                      Some(kind) => kind.parse::<ChallengeKind>(),
+                    };
+                    let difficulty = match args.next() {
+                      None => Ok(alarms[id].difficulty),
+                      Some(difficulty) => difficulty.parse::<Difficulty>(),
+                    };
+                    match (kind, difficulty) {
+                      (Ok(ChallengeKind::Location), Ok(_)) if !has_home => {
+                        build_plain_message("还没有记录床的位置，请先使用 #home 。")
+                      }
+                      (Ok(kind), Ok(difficulty)) => {
+                        alarms[id].challenge_kind = kind;
+                        alarms[id].difficulty = difficulty;
+                        build_plain_message(format!(
+                          "闹钟 {} 的严格模式挑战为{}，难度{}。{}",
+                          alarm_text,
+                          fmt_challenge_kind(kind),
+                          fmt_difficulty(difficulty),
+                          match alarms[id].is_strict {
+                            true => "",
+                            false => "闹钟开启严格模式后生效。",
+                          }
+                        ))
+                      }
+                      _ => build_fmt_message(|f| {
+                        f_bad_arguments(
+                          f,
+                          "参数有误，例如 #challenge 0 math hard ，挑战可以是 numerals、math、sentence、order 或 location ，难度可以是 easy、normal 或 hard 。",
+                        )
+                      }),
                     }
-                  }
-                };
-                reply_text_msg(to_send);
+                  },
+                ));
               }
-              "#purge" => {
-                let purged_cnt = {
+              "#home" => {
+                let user_id = message.sender_user_id();
+                let to_send = {
                   let state = store.state();
-                  let alarms_map = state.alarms.borrow();
-                  let timezone_map = state.timezone.borrow();
-                  let user_alarms = alarms_map.get(&message.sender_user_id());
-                  let now_utc = chrono::Local::now().naive_utc();
-                  if let None = user_alarms {
-                    reply_text_msg(build_plain_message("还一个闹钟都没有呢。"));
-                    continue;
-                  }
-                  let mut alarms = user_alarms.unwrap().borrow_mut();
-                  let tz = timezone_map.get(&message.sender_user_id());
-                  let tz = match tz {
-                    Some(tz) => {
-                      let tz = tz.parse::<Tz>().unwrap();
-                      Some(tz)
-                    }
-                    None => None,
+                  let mut homes_map = state.homes.borrow_mut();
+                  let distance = match cmd.arg().trim_end_matches('m') {
+                    "" => Ok(None),
+                    "off" => Err(true),
+                    arg => match arg.parse::<f64>() {
+                      Ok(distance) if (20.0..=5000.0).contains(&distance) => Ok(Some(distance)),
+                      _ => Err(false),
+                    },
                   };
-                  // awaiting https://doc.rust-lang.org/std/vec/struct.Vec.html#method.drain_filter
-                  let mut i = 0;
-                  let mut purged_cnt = 0;
-                  while i != alarms.len() {
-                    if alarms[i].is_informing != 0 {
-                      i += 1;
-                      continue;
+                  match (distance, homes_map.get(&user_id)) {
+                    (Ok(None), Some(home)) => build_plain_message(format!(
+                      "已记录床的位置，在 {:.0} 米以外分享实时位置即可完成离开床铺挑战。使用 #home <距离> 可以重新记录。",
+                      home.distance
+                    )),
+                    (Ok(distance), _) => {
+                      let distance = distance.unwrap_or(100.0);
+                      awaiting_home.insert(user_id, distance);
+                      build_plain_message(format!(
+                        "请在私聊中分享床的位置。之后在 {:.0} 米以外分享实时位置即可完成离开床铺挑战。",
+                        distance
+                      ))
                     }
-                    match tz {
-                      Some(tz) => {
-                        let next_alarm =
-                          get_next_schedule(&alarms[i].cron, &tz.from_utc_datetime(&now_utc));
-                        if !next_alarm.has_schedule() {
-                          alarms.remove(i);
-                          purged_cnt += 1;
-                        } else {
-                          i += 1;
-                        }
-                      }
-                      None => {
-                        let next_alarm = get_next_schedule(
-                          &alarms[i].cron,
-                          &chrono::Local.from_utc_datetime(&now_utc),
-                        );
-                        if !next_alarm.has_schedule() {
-                          alarms.remove(i);
-                          purged_cnt += 1;
-                        } else {
-                          i += 1;
+                    (Err(true), _) => {
+                      homes_map.remove(&user_id);
+                      awaiting_home.remove(&user_id);
+                      if let Some(alarms) = state.alarms.borrow().get(&user_id) {
+                        for alarm in alarms.borrow_mut().iter_mut() {
+                          if alarm.challenge_kind == ChallengeKind::Location {
+                            alarm.challenge_kind = ChallengeKind::default();
+                          }
                         }
                       }
+                      build_plain_message("已删除床的位置，离开床铺挑战的闹钟已改回数字转写。")
                     }
+                    (Err(false), _) => build_fmt_message(|f| {
+                      f_bad_arguments(f, "距离有误，例如 #home 200m ，范围为 20 到 5000 米。")
+                    }),
                   }
-                  purged_cnt
                 };
-                if purged_cnt > 0 {
-                  store.save().expect("Failed to save state");
-                  reply_text_msg(build_plain_message(format!(
-                    "已清除 {} 个闹钟。",
-                    purged_cnt
-                  )));
-                } else {
-                  reply_text_msg(build_plain_message("没有过期的闹钟。"));
-                }
+                store.save().expect("Failed to save state");
+                reply_text_msg(to_send);
               }
-              "#sleep" => {
-                reply_text_msg(build_fmt_message(|f| {
-                  f_bad_arguments(f, "没有这个命令，使用 #sleep! ")
-                }));
+              "#image" => {
+                reply_text_msg(with_alarm_id_and_arg(
+                  &store,
+                  &scheduler,
+                  message.sender_user_id(),
+                  &cmd,
+                  |alarms, id, arg| {
+                    let alarm_text = match alarms[id].title.as_str() {
+                      "" => format!("[{}]", id),
+                      title => format!("[{}] {}", id, title),
+                    };
+                    let args: Vec<&str> = arg.split_whitespace().collect();
+                    let settings = match args.as_slice() {
+                      ["off"] => Ok(None),
+                      [] | ["on"] => Ok(Some(RenderSettings::default())),
+                      [warp, noise] => match (warp.parse::<u32>(), noise.parse::<u32>()) {
+                        (Ok(warp), Ok(noise)) if warp <= 20 && noise <= 30 => {
+                          Ok(Some(RenderSettings { warp, noise }))
+                        }
+                        _ => Err(()),
+                      },
+                      _ => Err(()),
+                    };
+                    match settings {
+                      Ok(None) => {
+                        alarms[id].render = None;
+                        build_plain_message(format!("闹钟 {} 的挑战改为文字发送。", alarm_text))
+                      }
+                      Ok(Some(settings)) => {
+                        alarms[id].render = Some(settings);
+                        build_plain_message(format!(
+                          "闹钟 {} 的挑战改为图片发送，扭曲 {}，噪点 {}%。抄写句子的挑战仍以文字发送。",
+                          alarm_text, settings.warp, settings.noise
+                        ))
+                      }
+                      Err(_) => build_fmt_message(|f| {
+                        f_bad_arguments(
+                          f,
+                          "参数有误，例如 #image 0 、 #image 0 off 或 #image 0 6 8 ，扭曲最大 20 ，噪点最大 30 。",
+                        )
+                      }),
+                    }
+                  },
+                ));
               }
-              "#sleep!" => {
-                {
-                  let state = store.state();
-                  let mut sleeping_map = state.sleeping.borrow_mut();
+              "#regen" => {
+                reply_text_msg(with_alarm_id_and_arg(
+                  &store,
+                  &scheduler,
+                  message.sender_user_id(),
+                  &cmd,
+                  |alarms, id, arg| {
+                    let alarm_text = match alarms[id].title.as_str() {
+                      "" => format!("[{}]", id),
+                      title => format!("[{}] {}", id, title),
+                    };
+                    let after = match arg {
+                      "off" => Ok(0),
+                      arg => arg.parse::<u32>(),
+                    };
+                    match after {
+                      Ok(0) => {
+                        alarms[id].regenerate_after = 0;
+                        build_plain_message(format!("闹钟 {} 的挑战答错不会换题。", alarm_text))
+                      }
+                      Ok(after) => {
+                        alarms[id].regenerate_after = after;
+                        build_plain_message(format!(
+                          "闹钟 {} 的挑战每答错 {} 次换一道题。",
+                          alarm_text, after
+                        ))
+                      }
+                      Err(_) => build_fmt_message(|f| {
+                        f_bad_arguments(f, "参数有误，例如 #regen 0 3 或 #regen 0 off 。")
+                      }),
+                    }
+                  },
+                ));
+              }
+              "#shared" => {
+                if message.chat_id() > 0 {
+                  reply_text_msg(build_plain_message("共享闹钟只能在群里设置。"));
+                  continue;
+                }
+                if cmd.arg() == "" {
+                  let state = store.state();
+                  let shared_alarms_map = state.shared_alarms.borrow();
+                  let no_shared_alarms = RefCell::new(vec![]);
+                  let shared_alarms = shared_alarms_map
+                    .get(&message.chat_id())
+                    .unwrap_or(&no_shared_alarms)
+                    .borrow();
+                  reply_text_msg(build_plain_message(fmt_shared_alarms(
+                    &shared_alarms,
+                    &state.users.borrow(),
+                  )));
+                  continue;
+                }
+                let tz = {
+                  let state = store.state();
+                  let timezone_map = state.timezone.borrow();
+                  let tz = timezone_map.get(&message.sender_user_id());
+                  tz.map(|tz| tz.parse::<Tz>().unwrap())
+                };
+                let alarm_args = match tz {
+                  Some(tz) => parse_alarm_args(cmd.arg(), &tz, &*clock),
+                  None => parse_alarm_args(cmd.arg(), &chrono::Local, &*clock),
+                };
+                let cron_args = match alarm_args {
+                  Ok(cron_args) => cron_args,
+                  Err(_) => {
+                    reply_text_msg(build_fmt_message(|f| f_bad_arguments(f, "无效的表达式。")));
+                    continue;
+                  }
+                };
+                let id = {
+                  let state = store.state();
+                  let mut shared_alarms_map = state.shared_alarms.borrow_mut();
+                  let mut shared_alarms = shared_alarms_map
+                    .entry(message.chat_id())
+                    .or_insert_with(|| RefCell::new(vec![]))
+                    .borrow_mut();
+                  let id = next_shared_id(&shared_alarms);
+                  let mut shared = SharedAlarm::new(id, cron_args.cron(), cron_args.title());
+                  let alarm = shared.subscribe(message.sender_user_id(), message.chat_id());
+                  shared_alarms.push(shared);
+                  if let Some(alarm) = alarm {
+                    state.add_alarm(
+                      &mut state
+                        .alarms
+                        .borrow_mut()
+                        .entry(message.sender_user_id())
+                        .or_insert_with(|| RefCell::new(vec![]))
+                        .borrow_mut(),
+                      alarm,
+                    );
+                  }
+                  id
+                };
+                store.save().expect("Failed to save state");
+                scheduler.touch(message.sender_user_id());
+                reply_text_msg(build_plain_message(format!(
+                  "共享闹钟 [{}] 已设置，你已加入。群友使用 #join {} 加入，闹钟按各自的时区响铃，由各自关闭，结束后会在群里总结谁起床了。",
+                  id, id
+                )));
+              }
+              "#join" | "#leave" => {
+                if message.chat_id() > 0 {
+                  reply_text_msg(build_plain_message("共享闹钟只能在群里加入或退出。"));
+                  continue;
+                }
+                let id = match cmd.arg().parse::<i64>() {
+                  Ok(id) => id,
+                  Err(_) => {
+                    reply_text_msg(build_fmt_message(|f| {
+                      f_bad_arguments(f, "共享闹钟编号格式有误，使用 #shared 查看本群的共享闹钟。")
+                    }));
+                    continue;
+                  }
+                };
+                let user_id = message.sender_user_id();
+                let to_send = {
+                  let now = clock.timestamp();
+                  let state = store.state();
+                  let shared_alarms_map = state.shared_alarms.borrow();
+                  let no_shared_alarms = RefCell::new(vec![]);
+                  let mut shared_alarms = shared_alarms_map
+                    .get(&message.chat_id())
+                    .unwrap_or(&no_shared_alarms)
+                    .borrow_mut();
+                  let mut alarms_map = state.alarms.borrow_mut();
+                  let mut alarms = alarms_map
+                    .entry(user_id)
+                    .or_insert_with(|| RefCell::new(vec![]))
+                    .borrow_mut();
+                  let is_shared = |alarm: &Alarm| {
+                    alarm.chat_id == message.chat_id() && alarm.shared_id == Some(id)
+                  };
+                  match shared_alarms.iter().position(|shared| shared.id == id) {
+                    None => build_fmt_message(|f| {
+                      f_bad_arguments(f, "没有这个共享闹钟，使用 #shared 查看本群的共享闹钟。")
+                    }),
+                    Some(index) if cmd.cmd() == "#join" => {
+                      match shared_alarms[index].subscribe(user_id, message.chat_id()) {
+                        None => build_plain_message("你已经加入这个共享闹钟了。"),
+                        Some(alarm) => {
+                          let now_utc = clock.now().naive_utc();
+                          let tz = state
+                            .timezone
+                            .borrow()
+                            .get(&user_id)
+                            .map(|tz| tz.parse::<Tz>().unwrap());
+                          let next_alarm = match tz {
+                            Some(tz) => {
+                              get_next_schedule(&alarm, &tz.from_utc_datetime(&now_utc)).to_string()
+                            }
+                            None => {
+                              get_next_schedule(&alarm, &chrono::Local.from_utc_datetime(&now_utc))
+                                .to_string()
+                            }
+                          };
+                          let text = format!(
+                            "已加入共享闹钟 {}。{}",
+                            fmt_shared_alarm(&shared_alarms[index]),
+                            match next_alarm {
+                              Some(next_alarm) => format!("下次闹钟时间：{}", next_alarm),
+                              None => String::from("但是它看起来并不会响。"),
+                            }
+                          );
+                          state.add_alarm(&mut alarms, alarm);
+                          println!(
+                            "[{}] User {} joined shared alarm {} in group {}",
+                            now,
+                            user_id,
+                            id,
+                            message.chat_id()
+                          );
+                          build_plain_message(text)
+                        }
+                      }
+                    }
+                    Some(_)
+                      if alarms.iter().any(|alarm| {
+                        is_shared(alarm) && alarm.is_strict && alarm.ring.is_ringing()
+                      }) =>
+                    {
+                      build_plain_message("你不能退出正在进行的闹钟，请先关闭闹钟。")
+                    }
+                    Some(index) => {
+                      if shared_alarms[index].unsubscribe(user_id) {
+                        alarms.retain(|alarm| !is_shared(alarm));
+                        let text = format!(
+                          "已退出共享闹钟 {}。",
+                          fmt_shared_alarm(&shared_alarms[index])
+                        );
+                        println!(
+                          "[{}] User {} left shared alarm {} in group {}",
+                          now,
+                          user_id,
+                          id,
+                          message.chat_id()
+                        );
+                        if shared_alarms[index].subscribers.is_empty() {
+                          shared_alarms.remove(index);
+                          build_plain_message(format!("{}没有成员了，共享闹钟已删除。", text))
+                        } else {
+                          build_plain_message(text)
+                        }
+                      } else {
+                        build_plain_message("你没有加入这个共享闹钟。")
+                      }
+                    }
+                  }
+                };
+                store.save().expect("Failed to save state");
+                scheduler.touch(user_id);
+                scheduler.touch(message.chat_id());
+                reply_text_msg(to_send);
+              }
+              "#leaderboard" => {
+                if message.chat_id() > 0 {
+                  reply_text_msg(build_plain_message("排行榜只能在群里查看。"));
+                  continue;
+                }
+                let now = clock.timestamp();
+                let state = store.state();
+                let group_stats_map = state.group_stats.borrow();
+                let standings = group_stats_map
+                  .get(&message.chat_id())
+                  .map(|stats| stats.standings(now - RECORD_DAYS * 86400))
+                  .unwrap_or_default();
+                reply_text_msg(build_plain_message(fmt_standings(
+                  &format!("本群起床排行榜（近 {} 天）：", RECORD_DAYS),
+                  &standings,
+                  &state.users.borrow(),
+                )));
+              }
+              "#weekly" => {
+                if message.chat_id() > 0 {
+                  reply_text_msg(build_plain_message("每周总结只能在群里开启。"));
+                  continue;
+                }
+                let is_weekly = match cmd.arg() {
+                  "on" => true,
+                  "off" => false,
+                  _ => {
+                    reply_text_msg(build_fmt_message(|f| {
+                      f_bad_arguments(f, "参数有误，例如 #weekly on 或 #weekly off 。")
+                    }));
+                    continue;
+                  }
+                };
+                {
+                  let state = store.state();
+                  let mut group_stats_map = state.group_stats.borrow_mut();
+                  let stats = group_stats_map.entry(message.chat_id()).or_default();
+                  if is_weekly && !stats.is_weekly {
+                    stats.summary_sent = clock.timestamp();
+                  }
+                  stats.is_weekly = is_weekly;
+                }
+                store.save().expect("Failed to save state");
+                reply_text_msg(build_plain_message(match is_weekly {
+                  true => "已开启每周总结，每 7 天在群里发一次起床排行。",
+                  false => "已关闭每周总结。",
+                }));
+              }
+              "#next" => {
+                let state = store.state();
+                let alarms_map = state.alarms.borrow();
+                let timezone_map = state.timezone.borrow();
+                let vacation_map = state.vacation.borrow();
+                let user_alarms = alarms_map.get(&message.sender_user_id());
+                if let None = user_alarms {
+                  continue;
+                }
+                let alarms = user_alarms.unwrap().borrow();
+                let vacation = vacation_map.get(&message.sender_user_id());
+                let quiet_hours_map = state.quiet_hours.borrow();
+                let quiet_hours = quiet_hours_map.get(&message.sender_user_id());
+                let tz = timezone_map.get(&message.sender_user_id());
+                let (time_str, alarm_title, vacation_str) = match tz {
+                  Some(tz) => {
+                    let tz = tz.parse::<Tz>().unwrap();
+                    let next_alarm = get_recent_schedule(
+                      &alarms,
+                      tz,
+                      message.chat_id(),
+                      vacation,
+                      quiet_hours,
+                      &*clock,
+                    );
+                    (
+                      next_alarm.schedule().to_string(),
+                      next_alarm.alarm_title(),
+                      vacation.map(|vacation| fmt_vacation(vacation, tz)),
+                    )
+                  }
+                  None => {
+                    let next_alarm = get_recent_schedule(
+                      &alarms,
+                      chrono::Local.clone(),
+                      message.chat_id(),
+                      vacation,
+                      quiet_hours,
+                      &*clock,
+                    );
+                    (
+                      next_alarm.schedule().to_string(),
+                      next_alarm.alarm_title(),
+                      vacation.map(|vacation| fmt_vacation(vacation, chrono::Local)),
+                    )
+                  }
+                };
+                let vacation_str = match vacation_str {
+                  Some(vacation_str) => format!("{}\n", vacation_str),
+                  None => String::default(),
+                };
+                let to_send = match time_str {
+                  Some(time_str) => build_plain_message(format!(
+                    "{}下次闹钟时间：{} {}",
+                    vacation_str, time_str, alarm_title
+                  )),
+                  None => {
+                    if message.chat_id() < 0 {
+                      build_plain_message(format!(
+                        "{}这群看不到更多要响的闹钟了，不如回私聊试试看？",
+                        vacation_str
+                      ))
+                    } else {
+                      build_fmt_message(|f| {
+                        f_bad_arguments(
+                          f,
+                          format!("{}没有要响的闹钟了，去设置一些吧。", vacation_str),
+                        )
+                      })
+                    }
+                  }
+                };
+                reply_text_msg(to_send);
+              }
+              "#purge" => {
+                let purged_cnt = {
+                  let state = store.state();
+                  let alarms_map = state.alarms.borrow();
+                  let timezone_map = state.timezone.borrow();
+                  let user_alarms = alarms_map.get(&message.sender_user_id());
+                  let now_utc = clock.now().naive_utc();
+                  if let None = user_alarms {
+                    reply_text_msg(build_plain_message("还一个闹钟都没有呢。"));
+                    continue;
+                  }
+                  let mut alarms = user_alarms.unwrap().borrow_mut();
+                  let tz = timezone_map.get(&message.sender_user_id());
+                  let tz = match tz {
+                    Some(tz) => {
+                      let tz = tz.parse::<Tz>().unwrap();
+                      Some(tz)
+                    }
+                    None => None,
+                  };
+                  // awaiting https://doc.rust-lang.org/std/vec/struct.Vec.html#method.drain_filter
+                  let mut i = 0;
+                  let mut purged_cnt = 0;
+                  while i != alarms.len() {
+                    if alarms[i].ring.is_ringing() || alarms[i].is_broken() {
+                      i += 1;
+                      continue;
+                    }
+                    match tz {
+                      Some(tz) => {
+                        let next_alarm =
+                          get_next_schedule(&alarms[i], &tz.from_utc_datetime(&now_utc));
+                        if !next_alarm.has_schedule() {
+                          alarms.remove(i);
+                          purged_cnt += 1;
+                        } else {
+                          i += 1;
+                        }
+                      }
+                      None => {
+                        let next_alarm =
+                          get_next_schedule(&alarms[i], &chrono::Local.from_utc_datetime(&now_utc));
+                        if !next_alarm.has_schedule() {
+                          alarms.remove(i);
+                          purged_cnt += 1;
+                        } else {
+                          i += 1;
+                        }
+                      }
+                    }
+                  }
+                  purged_cnt
+                };
+                if purged_cnt > 0 {
+                  store.save().expect("Failed to save state");
+                  scheduler.touch(message.sender_user_id());
+                  reply_text_msg(build_plain_message(format!(
+                    "已清除 {} 个闹钟。",
+                    purged_cnt
+                  )));
+                } else {
+                  reply_text_msg(build_plain_message("没有过期的闹钟。"));
+                }
+              }
+              "#bedtime" => {
+                let to_send = match cmd.arg() {
+                  "" => {
+                    let state = store.state();
+                    let alarms_map = state.alarms.borrow();
+                    let timezone_map = state.timezone.borrow();
+                    let bedtime_map = state.bedtime.borrow();
+                    let vacation_map = state.vacation.borrow();
+                    let vacation = vacation_map.get(&message.sender_user_id());
+                    let settings = bedtime_map.get(&message.sender_user_id());
+                    let alarms = alarms_map.get(&message.sender_user_id());
+                    match (settings, alarms) {
+                      (None, _) => build_fmt_message(|f| {
+                        f_bad_arguments(f, "还没有设置睡眠时长，例如 #bedtime 8h 30m 。")
+                      }),
+                      (Some(_), None) => build_plain_message("没有要响的闹钟，没法推算就寝时间。"),
+                      (Some(settings), Some(alarms)) => {
+                        let alarms = alarms.borrow();
+                        let now = clock.now();
+                        let tz = timezone_map.get(&message.sender_user_id());
+                        let bedtime_text = match tz {
+                          Some(tz) => get_bedtime(
+                            &alarms,
+                            settings,
+                            vacation,
+                            &now.with_timezone(&tz.parse::<Tz>().unwrap()),
+                          )
+                          .map(|bedtime| {
+                            format!(
+                              "{} 提醒你准备睡觉，{}",
+                              bedtime.reminder.format("%R"),
+                              fmt_bedtime(&bedtime, settings)
+                            )
+                          }),
+                          None => get_bedtime(
+                            &alarms,
+                            settings,
+                            vacation,
+                            &now.with_timezone(&chrono::Local),
+                          )
+                          .map(|bedtime| {
+                            format!(
+                              "{} 提醒你准备睡觉，{}",
+                              bedtime.reminder.format("%R"),
+                              fmt_bedtime(&bedtime, settings)
+                            )
+                          }),
+                        };
+                        match bedtime_text {
+                          None => build_plain_message("没有要响的闹钟，没法推算就寝时间。"),
+                          Some(text) => build_plain_message(text),
+                        }
+                      }
+                    }
+                  }
+                  "off" => {
+                    let state = store.state();
+                    let mut bedtime_map = state.bedtime.borrow_mut();
+                    match bedtime_map.remove(&message.sender_user_id()) {
+                      None => build_plain_message("就寝提醒本来就是关闭的。"),
+                      Some(_) => build_plain_message("已关闭就寝提醒。"),
+                    }
+                  }
+                  arg => {
+                    let mut args = arg.split_whitespace();
+                    let sleep_duration = parse_duration(args.next().unwrap_or_default());
+                    let wind_down = match args.next() {
+                      None => Ok(1800),
+                      Some(wind_down) => parse_duration(wind_down),
+                    };
+                    match (sleep_duration, wind_down) {
+                      (Ok(sleep_duration), Ok(wind_down))
+                        if sleep_duration > 0 && sleep_duration < 86400 && wind_down < 43200 =>
+                      {
+                        let state = store.state();
+                        let mut bedtime_map = state.bedtime.borrow_mut();
+                        bedtime_map.insert(
+                          message.sender_user_id(),
+                          Bedtime::new(sleep_duration, wind_down),
+                        );
+                        build_plain_message(format!(
+                          "已设置睡眠时长 {}，会提前 {} 提醒你准备睡觉。",
+                          format_duration(sleep_duration),
+                          format_duration(wind_down)
+                        ))
+                      }
+                      _ => build_fmt_message(|f| f_bad_arguments(f, "无效的时长。")),
+                    }
+                  }
+                };
+                store.save().expect("Failed to save state");
+                scheduler.touch(message.sender_user_id());
+                reply_text_msg(to_send);
+              }
+              "#nobedtime" => {
+                reply_text_msg(with_alarm_id(
+                  &store,
+                  &scheduler,
+                  message.sender_user_id(),
+                  &cmd,
+                  |alarms, id| {
+                    alarms[id].is_bedtime_off = !alarms[id].is_bedtime_off;
+                    let alarm_text = match alarms[id].title.as_str() {
+                      "" => format!("[{}]", id),
+                      title => format!("[{}] {}", id, title),
+                    };
+                    build_plain_message(match alarms[id].is_bedtime_off {
+                      true => format!("闹钟 {} 不再用于推算就寝时间。", alarm_text),
+                      false => format!("闹钟 {} 将用于推算就寝时间。", alarm_text),
+                    })
+                  },
+                ));
+              }
+              "#vacation" => {
+                let tz = {
+                  let state = store.state();
+                  let timezone_map = state.timezone.borrow();
+                  let tz = timezone_map.get(&message.sender_user_id());
+                  tz.map(|tz| tz.parse::<Tz>().unwrap())
+                };
+                let to_send = match cmd.arg() {
+                  "" => {
+                    let state = store.state();
+                    let vacation_map = state.vacation.borrow();
+                    match (vacation_map.get(&message.sender_user_id()), tz) {
+                      (None, _) => build_plain_message("没有开启假期模式。"),
+                      (Some(vacation), Some(tz)) => build_plain_message(fmt_vacation(vacation, tz)),
+                      (Some(vacation), None) => {
+                        build_plain_message(fmt_vacation(vacation, chrono::Local))
+                      }
+                    }
+                  }
+                  "off" => {
+                    let state = store.state();
+                    let mut vacation_map = state.vacation.borrow_mut();
+                    match vacation_map.remove(&message.sender_user_id()) {
+                      None => build_plain_message("没有开启假期模式。"),
+                      Some(_) => build_plain_message("假期模式已关闭，闹钟已恢复。"),
+                    }
+                  }
+                  arg => {
+                    let args = parse_command_msg(arg);
+                    let args = match args.cmd() {
+                      "until" => parse_command_msg(args.arg()),
+                      _ => args,
+                    };
+                    let tags = parse_tags(args.arg());
+                    let date = chrono::NaiveDate::parse_from_str(args.cmd(), "%Y-%m-%d");
+                    let until = match date {
+                      Err(_) => None,
+                      Ok(date) => match tz {
+                        Some(tz) => tz
+                          .from_local_datetime(&date.and_hms(0, 0, 0))
+                          .earliest()
+                          .map(|d| d.timestamp()),
+                        None => chrono::Local
+                          .from_local_datetime(&date.and_hms(0, 0, 0))
+                          .earliest()
+                          .map(|d| d.timestamp()),
+                      },
+                    };
+                    match until {
+                      None => build_fmt_message(|f| {
+                        f_bad_arguments(f, "日期格式有误，例如 #vacation until 2026-10-08 。")
+                      }),
+                      Some(until) if until <= clock.timestamp() => {
+                        build_plain_message("这个日期已经过去了。")
+                      }
+                      Some(until) => {
+                        let vacation = Vacation {
+                          until,
+                          tag: tags.first().cloned(),
+                        };
+                        let to_send = match tz {
+                          Some(tz) => fmt_vacation(&vacation, tz),
+                          None => fmt_vacation(&vacation, chrono::Local),
+                        };
+                        let state = store.state();
+                        let mut vacation_map = state.vacation.borrow_mut();
+                        vacation_map.insert(message.sender_user_id(), vacation);
+                        build_plain_message(to_send)
+                      }
+                    }
+                  }
+                };
+                store.save().expect("Failed to save state");
+                scheduler.touch(message.sender_user_id());
+                reply_text_msg(to_send);
+              }
+              "#timer" => {
+                handle_timer(false);
+              }
+              "#timer!" => {
+                handle_timer(true);
+              }
+              "#pomodoro" => {
+                handle_pomodoro(false);
+              }
+              "#pomodoro!" => {
+                handle_pomodoro(true);
+              }
+              "#timers" => {
+                let now = clock.timestamp();
+                let state = store.state();
+                let countdowns_map = state.countdowns.borrow();
+                let lines: Vec<String> = match countdowns_map.get(&message.sender_user_id()) {
+                  None => vec![],
+                  Some(countdowns) => countdowns
+                    .borrow()
+                    .iter()
+                    .map(|countdown| fmt_countdown(countdown, now))
+                    .collect(),
+                };
+                reply_text_msg(build_plain_message(match lines.is_empty() {
+                  true => String::from("没有正在进行的计时。"),
+                  false => lines.join("\n"),
+                }));
+              }
+              "#cancel" => {
+                let to_send = match cmd.arg().parse::<i64>() {
+                  Err(_) => build_fmt_message(|f| f_bad_arguments(f, "请提供计时编号。")),
+                  Ok(id) => {
+                    let now = clock.timestamp();
+                    let state = store.state();
+                    let countdowns_map = state.countdowns.borrow();
+                    let cancelled = match countdowns_map.get(&message.sender_user_id()) {
+                      None => None,
+                      Some(countdowns) => {
+                        let mut countdowns = countdowns.borrow_mut();
+                        countdowns
+                          .iter()
+                          .position(|countdown| countdown.id == id)
+                          .map(|index| countdowns.remove(index))
+                      }
+                    };
+                    match cancelled {
+                      None => build_fmt_message(|f| f_bad_arguments(f, "没有这个计时。")),
+                      Some(countdown) => {
+                        println!(
+                          "[{}] Cancelled countdown {} of user {}",
+                          now,
+                          id,
+                          message.sender_user_id()
+                        );
+                        build_plain_message(match countdown.pomodoro {
+                          None => format!("计时 [{}] 已取消。", id),
+                          Some(_) => format!(
+                            "番茄钟 [{}] 已取消，{}",
+                            id,
+                            fmt_pomodoro_summary(&countdown, now)
+                          ),
+                        })
+                      }
+                    }
+                  }
+                };
+                store.save().expect("Failed to save state");
+                scheduler.touch(message.sender_user_id());
+                reply_text_msg(to_send);
+              }
+              "#quiet" => {
+                let user_id = message.sender_user_id();
+                let to_send = {
+                  let state = store.state();
+                  let mut quiet_hours_map = state.quiet_hours.borrow_mut();
+                  let args = parse_command_msg(cmd.arg());
+                  match (args.cmd(), parse_time_window(args.cmd())) {
+                    ("", _) => match quiet_hours_map.get(&user_id) {
+                      None => build_plain_message("没有设置免打扰时段。"),
+                      Some(quiet_hours) => build_plain_message(fmt_quiet_hours(quiet_hours)),
+                    },
+                    ("off", _) => match quiet_hours_map.remove(&user_id) {
+                      None => build_plain_message("没有设置免打扰时段。"),
+                      Some(_) => build_plain_message("免打扰时段已关闭。"),
+                    },
+                    (_, Ok((start, end)))
+                      if args.arg() == "" || args.arg() == "defer" || args.arg() == "drop" =>
+                    {
+                      let quiet_hours = QuietHours {
+                        start,
+                        end,
+                        is_dropping: args.arg() == "drop",
+                      };
+                      let to_send = build_plain_message(format!(
+                        "{}严格模式的闹钟不受影响。",
+                        fmt_quiet_hours(&quiet_hours)
+                      ));
+                      quiet_hours_map.insert(user_id, quiet_hours);
+                      to_send
+                    }
+                    _ => build_fmt_message(|f| {
+                      f_bad_arguments(
+                        f,
+                        "参数有误，例如 #quiet 23:00-07:00 defer 或 #quiet 23:00-07:00 drop 。",
+                      )
+                    }),
+                  }
+                };
+                store.save().expect("Failed to save state");
+                scheduler.touch(user_id);
+                reply_text_msg(to_send);
+              }
+              "#contact" => {
+                let user_id = message.sender_user_id();
+                let to_send = {
+                  let state = store.state();
+                  let mut contacts_map = state.contacts.borrow_mut();
+                  let fmt_pending = |contact: &EmergencyContact| {
+                    build_plain_message(format!(
+                      "{}请让对方私聊我发送 #buddy {} 确认。每人只能有一位紧急联系人，重新设置会替换原来的。",
+                      fmt_contact(contact),
+                      user_id
+                    ))
+                  };
+                  let words: Vec<&str> = cmd.arg().split_whitespace().collect();
+                  let after = words.first().map(|word| word.parse::<i64>());
+                  let is_calling = words.get(1) == Some(&"call");
+                  let invitee = match words.get(if is_calling { 2 } else { 1 }) {
+                    None => None,
+                    Some(word) if word.starts_with('@') && word.len() > 1 => {
+                      Some(Ok(word[1..].to_lowercase()))
+                    }
+                    Some(word) => Some(
+                      word
+                        .parse::<i64>()
+                        .ok()
+                        .filter(|id| *id > 0 && *id != user_id)
+                        .map(|id| id.to_string())
+                        .ok_or(()),
+                    ),
+                  };
+                  let existing = contacts_map.get(&user_id).cloned();
+                  match (words.as_slice(), after, invitee, existing) {
+                    ([], _, _, None) => build_plain_message("没有设置紧急联系人。"),
+                    ([], _, _, Some(contact)) if contact.contact_id == 0 => fmt_pending(&contact),
+                    ([], _, _, Some(contact)) => build_plain_message(fmt_contact(&contact)),
+                    (["off"], _, _, _) => match contacts_map.remove(&user_id) {
+                      None => build_plain_message("没有设置紧急联系人。"),
+                      Some(_) => build_plain_message("紧急联系人已移除。"),
+                    },
+                    (_, Some(Ok(after)), invitee, existing)
+                      if after > 0
+                        && after <= 20
+                        && words.len() <= if is_calling { 3 } else { 2 } =>
+                    {
+                      let contact = match (invitee, existing) {
+                        (Some(Err(_)), _) | (None, None) => None,
+                        (Some(Ok(invitee)), Some(existing)) if existing.invitee == invitee => {
+                          Some(existing)
+                        }
+                        (Some(Ok(invitee)), _) => Some(EmergencyContact {
+                          contact_id: 0,
+                          after,
+                          is_calling,
+                          invitee,
+                        }),
+                        (None, Some(existing)) => Some(existing),
+                      };
+                      match contact {
+                        None => build_fmt_message(|f| {
+                          f_bad_arguments(
+                            f,
+                            "请指定紧急联系人的用户编号或用户名，例如 #contact 3 @username 或 #contact 3 call @username 。",
+                          )
+                        }),
+                        Some(contact) => {
+                          let contact = EmergencyContact {
+                            after,
+                            is_calling,
+                            ..contact
+                          };
+                          let to_send = match contact.contact_id {
+                            0 => fmt_pending(&contact),
+                            _ => build_plain_message(fmt_contact(&contact)),
+                          };
+                          contacts_map.insert(user_id, contact);
+                          to_send
+                        }
+                      }
+                    }
+                    _ => build_fmt_message(|f| {
+                      f_bad_arguments(
+                        f,
+                        "参数有误，例如 #contact 3 @username 或 #contact 3 call @username 。每人只能有一位紧急联系人。",
+                      )
+                    }),
+                  }
+                };
+                store.save().expect("Failed to save state");
+                reply_text_msg(to_send);
+              }
+              "#buddy" | "#unbuddy" => {
+                let is_joining = cmd.cmd() == "#buddy";
+                let to_send = match cmd.arg().parse::<i64>() {
+                  _ if message.chat_id() < 0 => build_plain_message("请在私聊中使用这个命令。"),
+                  Err(_) => build_fmt_message(|f| f_bad_arguments(f, "请提供对方的用户编号。")),
+                  Ok(user_id) => {
+                    let state = store.state();
+                    let mut contacts_map = state.contacts.borrow_mut();
+                    let sender_id = message.sender_user_id();
+                    let username = usernames.get(&sender_id).map(String::as_str).unwrap_or("");
+                    match contacts_map.get_mut(&user_id) {
+                      Some(contact)
+                        if is_joining
+                          && contact.contact_id == 0
+                          && contact.is_invitee(sender_id, username) =>
+                      {
+                        contact.contact_id = message.sender_user_id();
+                        println!(
+                          "[{}] User {} became emergency contact of user {}",
+                          clock.timestamp(),
+                          message.sender_user_id(),
+                          user_id
+                        );
+                        build_plain_message("你已成为对方的紧急联系人。")
+                      }
+                      Some(contact)
+                        if !is_joining && contact.contact_id == message.sender_user_id() =>
+                      {
+                        contact.contact_id = 0;
+                        build_plain_message("你已不再是对方的紧急联系人。")
+                      }
+                      _ => {
+                        build_fmt_message(|f| f_bad_arguments(f, "对方没有邀请你作为紧急联系人。"))
+                      }
+                    }
+                  }
+                };
+                store.save().expect("Failed to save state");
+                reply_text_msg(to_send);
+              }
+              "#ack" => {
+                let to_send = match cmd.arg().parse::<i64>() {
+                  Err(_) => build_fmt_message(|f| f_bad_arguments(f, "请提供对方的用户编号。")),
+                  Ok(user_id) => {
+                    let state = store.state();
+                    let contacts_map = state.contacts.borrow();
+                    let alarms_map = state.alarms.borrow();
+                    match (contacts_map.get(&user_id), alarms_map.get(&user_id)) {
+                      (Some(contact), Some(alarms))
+                        if contact.contact_id == message.sender_user_id() =>
+                      {
+                        let mut acknowledged = 0;
+                        for alarm in alarms.borrow_mut().iter_mut() {
+                          if alarm.ring.acknowledge_escalation() {
+                            acknowledged += 1;
+                          }
+                        }
+                        println!(
+                          "[{}] Emergency contact {} acknowledged {} alarms of user {}",
+                          clock.timestamp(),
+                          message.sender_user_id(),
+                          acknowledged,
+                          user_id
+                        );
+                        build_plain_message("收到，这次不会再提醒你了。")
+                      }
+                      _ => build_fmt_message(|f| f_bad_arguments(f, "你不是对方的紧急联系人。")),
+                    }
+                  }
+                };
+                store.save().expect("Failed to save state");
+                reply_text_msg(to_send);
+              }
+              "#incoming" => {
+                let user_id = message.sender_user_id();
+                let to_send = {
+                  let state = store.state();
+                  let mut incoming_call_map = state.incoming_call.borrow_mut();
+                  let action = match cmd.arg() {
+                    "" => Ok(None),
+                    "off" => Ok(Some(IncomingCall::Ignore)),
+                    "snooze" => Ok(Some(IncomingCall::Snooze)),
+                    "dismiss" => Ok(Some(IncomingCall::Dismiss)),
+                    _ => Err(()),
+                  };
+                  match action {
+                    Ok(None) => build_plain_message(fmt_incoming_call(
+                      incoming_call_map
+                        .get(&user_id)
+                        .cloned()
+                        .unwrap_or(IncomingCall::Snooze),
+                    )),
+                    Ok(Some(action)) => {
+                      incoming_call_map.insert(user_id, action);
+                      build_plain_message(fmt_incoming_call(action))
+                    }
+                    Err(_) => build_fmt_message(|f| {
+                      f_bad_arguments(f, "参数有误，可以是 snooze、dismiss 或 off 。")
+                    }),
+                  }
+                };
+                store.save().expect("Failed to save state");
+                reply_text_msg(to_send);
+              }
+              "#sleep" => {
+                reply_text_msg(build_fmt_message(|f| {
+                  f_bad_arguments(f, "没有这个命令，使用 #sleep! ")
+                }));
+              }
+              "#sleep!" => {
+                let now = clock.timestamp();
+                let until = match cmd.arg() {
+                  "" => None,
+                  arg => {
+                    let tz = {
+                      let state = store.state();
+                      let timezone_map = state.timezone.borrow();
+                      let tz = timezone_map.get(&message.sender_user_id());
+                      tz.map(|tz| tz.parse::<Tz>().unwrap())
+                    };
+                    let until = match tz {
+                      Some(tz) => parse_sleep_until(arg, tz, now),
+                      None => parse_sleep_until(arg, chrono::Local, now),
+                    };
+                    match until {
+                      Ok(until) if (60..=86400).contains(&(until - now)) => Some(until),
+                      _ => {
+                        reply_text_msg(build_fmt_message(|f| {
+                          f_bad_arguments(
+                            f,
+                            "参数有误，例如 #sleep! 8h 或 #sleep! until 07:30 ，最长 24 小时。",
+                          )
+                        }));
+                        continue;
+                      }
+                    }
+                  }
+                };
+                {
+                  let state = store.state();
+                  let mut sleeping_map = state.sleeping.borrow_mut();
                   let user_sleeping = sleeping_map.get(&message.sender_user_id());
                   if let None = user_sleeping {
                     sleeping_map.insert(message.sender_user_id(), RefCell::new(vec![]));
                   }
-                  let user_sleeping = sleeping_map.get(&message.sender_user_id()).unwrap();
-                  user_sleeping.borrow_mut().push(message.chat_id());
+                  let mut user_sleeping = sleeping_map
+                    .get(&message.sender_user_id())
+                    .unwrap()
+                    .borrow_mut();
+                  user_sleeping.retain(|sleep| sleep.chat_id != message.chat_id());
+                  user_sleeping.push(Sleep {
+                    chat_id: message.chat_id(),
+                    until,
+                  });
                 }
                 store.save().expect("Failed to save state");
+                scheduler.touch(message.sender_user_id());
                 let req = SetChatMemberStatus::builder()
                   .chat_id(message.chat_id())
                   .user_id(message.sender_user_id())
                   .status(ChatMemberStatus::Restricted(
                     ChatMemberStatusRestricted::builder()
                       .is_member(true)
-                      .restricted_until_date(1)
+                      .restricted_until_date(until.unwrap_or(1))
                       .permissions(ChatPermissions::builder().can_send_messages(false).build())
                       .build(),
                   ))
                   .build();
-                tdlib.send(&req.to_json().expect("Bad JSON"));
-                reply_text_msg(build_plain_message("See you next time!"));
+                tdlib.request(
+                  &req,
+                  Request::ChatMember {
+                    chat_id: message.chat_id(),
+                    user_id: message.sender_user_id(),
+                    is_muting: true,
+                  },
+                );
+                reply_text_msg(build_plain_message(match until {
+                  None => String::from("See you next time!"),
+                  Some(until) => format!(
+                    "See you next time! {}后自动解除禁言。",
+                    format_duration(until - now)
+                  ),
+                }));
+              }
+              "#sleeping" => {
+                if message.chat_id() > 0 {
+                  reply_text_msg(build_plain_message("只能在群里查看谁在睡觉。"));
+                  continue;
+                }
+                let now = clock.timestamp();
+                let state = store.state();
+                let mut sleepers: Vec<(i64, Option<i64>)> = state
+                  .sleeping
+                  .borrow()
+                  .iter()
+                  .flat_map(|(user_id, user_sleeping)| {
+                    user_sleeping
+                      .borrow()
+                      .iter()
+                      .filter(|sleep| sleep.chat_id == message.chat_id())
+                      .map(|sleep| (*user_id, sleep.until))
+                      .collect::<Vec<(i64, Option<i64>)>>()
+                  })
+                  .collect();
+                sleepers.sort_by_key(|(_, until)| until.unwrap_or(std::i64::MAX));
+                reply_text_msg(build_plain_message(fmt_sleepers(
+                  &sleepers,
+                  &state.users.borrow(),
+                  now,
+                )));
               }
               _ => {
                 continue;
               }
             }
           }
-          _ => (),
+          MessageContent::MessageLocation(location) => {
+            let user_id = message.sender_user_id();
+            let latitude = location.location().latitude() as f64;
+            let longitude = location.location().longitude() as f64;
+            if message.chat_id() > 0 {
+              if let Some(distance) = awaiting_home.remove(&user_id) {
+                {
+                  let state = store.state();
+                  state.homes.borrow_mut().insert(
+                    user_id,
+                    Home {
+                      latitude,
+                      longitude,
+                      distance,
+                    },
+                  );
+                }
+                store.save().expect("Failed to save state");
+                reply_text_msg(build_plain_message(format!(
+                  "已记录床的位置。使用 #challenge <编号> location 让严格模式闹钟要求你走到 {:.0} 米以外。",
+                  distance
+                )));
+                continue;
+              }
+            }
+            // A location picked on the map or forwarded from elsewhere says
+            // nothing about where the user is, only their own live location
+            // does.
+            let refusal = match (message.forward_info(), location.live_period()) {
+              (Some(_), _) => Some("转发的位置不算数，请分享你自己的实时位置。"),
+              (None, 0) => Some("请分享实时位置，在地图上选的位置不算数。"),
+              _ => None,
+            };
+            if let Some(refusal) = refusal {
+              let is_challenged = {
+                let state = store.state();
+                has_location_challenge(&state, user_id)
+              };
+              if is_challenged {
+                reply_text_msg(build_plain_message(refusal));
+              }
+              continue;
+            }
+            live_locations.insert(message.chat_id(), message.id());
+            let is_fulfilled = {
+              let state = store.state();
+              check_location(&tdlib, &state, &*clock, user_id, latitude, longitude, false)
+            };
+            if is_fulfilled {
+              store.save().expect("Failed to save state");
+              scheduler.touch(user_id);
+            }
+          }
+          _ => (),
+        }
+      }
+      "updateMessageContent" => {
+        let update: UpdateMessageContent = serde_json::from_str(json.as_str()).unwrap_or_default();
+        let user_id = update.chat_id();
+        if user_id <= 0 || live_locations.get(&user_id) != Some(&update.message_id()) {
+          continue;
+        }
+        if let MessageContent::MessageLocation(location) = update.new_content() {
+          let latitude = location.location().latitude() as f64;
+          let longitude = location.location().longitude() as f64;
+          let is_fulfilled = {
+            let state = store.state();
+            check_location(&tdlib, &state, &*clock, user_id, latitude, longitude, true)
+          };
+          if is_fulfilled {
+            store.save().expect("Failed to save state");
+            scheduler.touch(user_id);
+          }
+        }
+      }
+      "updateMessageSendFailed" => {
+        let update: UpdateMessageSendFailed =
+          serde_json::from_str(json.as_str()).unwrap_or_default();
+        remove_challenge_image(update.message());
+      }
+      "updateMessageSendSucceeded" => {
+        let update: UpdateMessageSendSucceeded =
+          serde_json::from_str(json.as_str()).unwrap_or_default();
+        let message = update.message();
+        remove_challenge_image(message);
+        if message.chat_id() <= 0 {
+          continue;
+        }
+        {
+          let now = clock.timestamp();
+          let state = store.state();
+          let alarms_map = state.alarms.borrow();
+          let mut alarms = match alarms_map.get(&message.chat_id()) {
+            None => continue,
+            Some(alarms) => alarms.borrow_mut(),
+          };
+          for alarm in alarms.iter_mut() {
+            if alarm.ring.take_pin() {
+              let req = PinChatMessage::builder()
+                .chat_id(message.chat_id())
+                .message_id(message.id())
+                .build();
+              tdlib.send(&req.to_json().expect("Bad JSON"));
+              println!(
+                "[{}] Pinned message {} for alarm {}",
+                now,
+                message.id(),
+                alarm
+              );
+              break;
+            }
+          }
         }
+        store.save().expect("Failed to save state");
       }
       "updateCall" => {
         let update_call: UpdateCall = serde_json::from_str(json.as_str()).unwrap_or_default();
@@ -707,42 +2410,17 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
               .call_id(call.id())
               .build();
             tdlib.send(&req.to_json().expect("Bad JSON"));
+            {
+              let state = store.state();
+              handle_incoming_call(&tdlib, &state, &*clock, user_id);
+            }
+            store.save().expect("Failed to save state");
+            scheduler.touch(user_id);
           }
           continue;
         }
-        let handle_help_message =
-          |alarm: &mut Alarm,
-           now: i64,
-           is_discard: bool,
-           users_map: &std::collections::HashMap<i64, std::string::String>,
-           user_id: i64| {
-            let user_name = users_map.get(&user_id);
-            let user_name = match user_name {
-              None => "他",
-              Some(name) => name,
-            };
-            if alarm.chat_id < 0 && (alarm.is_informing == 1 || alarm.is_informing == 2) {
-              let req = SendChatAction::builder()
-                .chat_id(alarm.chat_id)
-                .action(ChatAction::Typing(ChatActionTyping::builder().build()))
-                .build();
-              tdlib.send(&req.to_json().expect("Bad JSON"));
-              let req = SendMessage::builder()
-                .chat_id(alarm.chat_id)
-                .input_message_content(build_fmt_message(|f| {
-                  f_help_alarm(f, user_name, user_id, is_discard)
-                }))
-                .build();
-              tdlib.send(&req.to_json().expect("Bad JSON"));
-              println!(
-                "[{}] Sent help message for alarm {} due to chat_id < 0, is informing: {}",
-                now, alarm, alarm.is_informing
-              );
-              alarm.is_informing += 2;
-            }
-          };
         let handle_discard_error = |is_discard: bool| {
-          let now = chrono::Local::now().timestamp();
+          let now = clock.timestamp();
           let state = store.state();
           let users_map = state.users.borrow();
           let alarms_map = state.alarms.borrow();
@@ -753,70 +2431,54 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
           let user_alarms = user_alarms.unwrap();
           let mut alarms = user_alarms.borrow_mut();
           for alarm in alarms.iter_mut() {
-            if alarm.is_pending {
-              alarm.is_pending = false;
+            if alarm.ring.is_on_call(call.id()) {
+              if is_discard {
+                alarm.ring.hang_up();
+              } else {
+                alarm.ring.fail_call();
+              }
               println!(
                 "[{}] Will alarm {} again due to unfulfilled call, is discard: {}",
                 now, alarm, is_discard
               );
-              handle_help_message(alarm, now, is_discard, &*users_map, user_id);
-              break;
+              send_help_message(&tdlib, alarm, now, is_discard, &users_map, user_id);
             }
           }
         };
         match call.state() {
           CallState::ExchangingKeys(_) => {
-            let now = chrono::Local::now().timestamp();
+            let now = clock.timestamp();
             let state = store.state();
             let alarms_map = state.alarms.borrow();
             let users_map = state.users.borrow();
             let mut sleeping_map = state.sleeping.borrow_mut();
-            let user_alarms = alarms_map.get(&user_id);
-            if let None = user_alarms {
-              continue;
-            }
-            let user_alarms = user_alarms.unwrap();
+            let no_alarms = RefCell::new(vec![]);
+            let user_alarms = alarms_map.get(&user_id).unwrap_or(&no_alarms);
             let mut alarms = user_alarms.borrow_mut();
+            let mut is_holding = false;
             for alarm in alarms.iter_mut() {
-              if alarm.is_pending {
-                alarm.is_pending = false;
-                if !alarm.is_strict {
-                  alarm.is_informing = 0;
-                  unlock_user(user_id, &mut sleeping_map);
-                  println!("[{}] Fulfilled alarm {} due to answering call", now, alarm);
-                } else {
-                  alarm.is_informing += 1;
-                  let (challenge, answer, map) = generate_strict_challenge();
-                  alarm.strict_challenge = answer;
-                  let req = SendChatAction::builder()
-                    .chat_id(user_id)
-                    .action(ChatAction::Typing(ChatActionTyping::builder().build()))
-                    .build();
-                  tdlib.send(&req.to_json().expect("Bad JSON"));
-                  let req = SendMessage::builder()
-                    .chat_id(user_id)
-                    .input_message_content(build_fmt_message(|f| {
-                      f_strict_challenge(f, &challenge, &map)
-                    }))
-                    .build();
-                  tdlib.send(&req.to_json().expect("Bad JSON"));
-                  println!(
-                    "[{}] Challenged user with {} in need of closing alarm {}",
-                    now, alarm.strict_challenge, alarm
-                  );
-                  println!(
-                    "[{}] Will alarm {} again due to unfulfilled strict call even it was answered",
-                    now, alarm
-                  );
-                  handle_help_message(alarm, now, true, &*users_map, user_id);
-                }
-                let req = DiscardCall::builder()
-                  .is_disconnected(true)
-                  .call_id(call.id())
-                  .build();
-                tdlib.send(&req.to_json().expect("Bad JSON"));
-                break;
+              if !alarm.ring.is_on_call(call.id()) {
+                continue;
+              }
+              if alarm.min_call_duration > 0 {
+                alarm.ring.hold(now + alarm.min_call_duration);
+                is_holding = true;
+                println!(
+                  "[{}] Holding call of alarm {} for {} seconds",
+                  now, alarm, alarm.min_call_duration
+                );
+                continue;
               }
+              answer_call(&tdlib, alarm, now, &users_map, &mut sleeping_map, &state);
+            }
+            // Calls placed for countdowns have no pending alarm, but are hung up
+            // all the same once answered.
+            if !is_holding {
+              let req = DiscardCall::builder()
+                .is_disconnected(true)
+                .call_id(call.id())
+                .build();
+              tdlib.send(&req.to_json().expect("Bad JSON"));
             }
           }
           CallState::Discarded(_) => {
@@ -828,119 +2490,1194 @@ pub fn start_handler(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle
           _ => {}
         }
         store.save().expect("Failed to save state");
+        scheduler.touch(user_id);
       }
       _ => {}
     };
   })
 }
 
-pub fn start_cron(tdlib: Arc<Tdlib>, store: Arc<Store>) -> thread::JoinHandle<()> {
-  let mut service = CronService::new();
+/// Starts sending the requests queued in `tdlib`, and logs the metrics of
+/// the queue every minute it was busy.
+pub fn start_sender(tdlib: Arc<Client>, clock: Arc<dyn Clock>) -> thread::JoinHandle<()> {
+  let reporter = tdlib.clone();
+  thread::spawn(move || loop {
+    thread::sleep(time::Duration::from_secs(60));
+    let metrics = reporter.take_metrics();
+    if metrics.sent > 0 || metrics.depth > 0 {
+      println!(
+        "[{}] Outbox depth {} (max {}), sent {}, dropped {}, flood waits {}",
+        clock.timestamp(),
+        metrics.depth,
+        metrics.max_depth,
+        metrics.sent,
+        metrics.dropped,
+        metrics.flood_waits
+      );
+    }
+  });
+  thread::spawn(move || tdlib.run())
+}
+
+pub fn start_cron(
+  tdlib: Arc<Client>,
+  store: Arc<Store>,
+  clock: Arc<dyn Clock>,
+  scheduler: Arc<Scheduler>,
+) -> thread::JoinHandle<()> {
+  let mut service = CronService::new(clock.clone());
+  {
+    let state = store.state();
+    let mut user_ids: HashSet<i64> = state.alarms.borrow().keys().cloned().collect();
+    user_ids.extend(state.vacation.borrow().keys());
+    user_ids.extend(state.countdowns.borrow().keys());
+    user_ids.extend(state.sleeping.borrow().keys());
+    user_ids.extend(state.shared_alarms.borrow().keys());
+    for user_id in user_ids {
+      scheduler.reschedule(&state, user_id, service.last_tick());
+    }
+  }
   thread::spawn(move || loop {
-    thread::sleep(time::Duration::from_secs(1));
+    scheduler.wait(&*clock, time::Duration::from_secs(MAX_SLEEP));
     service.tick(|last_tick, now| {
       {
         let state = store.state();
-        let alarms_map = state.alarms.borrow();
-        let timezone_map = state.timezone.borrow();
-        let last_tick_utc = chrono::NaiveDateTime::from_timestamp(last_tick, 0);
-        for (user_id, user_alarms) in &*alarms_map {
-          let tz = timezone_map.get(user_id);
-          let tz = match tz {
-            Some(tz) => {
-              let tz = tz.parse::<Tz>().unwrap();
-              Some(tz)
-            }
-            None => None,
-          };
-          let mut alarms = user_alarms.borrow_mut();
-          for alarm in alarms.iter_mut() {
-            let (should_alarm, should_stop) = match tz {
-              Some(tz) => {
-                let next_alarm = match alarm.is_informing {
-                  0 => get_next_schedule(&alarm.cron, &tz.from_utc_datetime(&last_tick_utc))
-                    .to_timestamp(),
-                  _ => alarm.reschedule,
-                };
-                (
-                  next_alarm > last_tick && next_alarm <= now,
-                  next_alarm <= last_tick,
-                )
-              }
-              None => {
-                let next_alarm = match alarm.is_informing {
-                  0 => get_next_schedule(
-                    &alarm.cron,
-                    &chrono::Local.from_utc_datetime(&last_tick_utc),
-                  )
-                  .to_timestamp(),
-                  _ => alarm.reschedule,
-                };
-                (
-                  next_alarm > last_tick && next_alarm <= now,
-                  next_alarm <= last_tick,
-                )
+        for user_id in scheduler.take_dirty() {
+          scheduler.reschedule(&state, user_id, last_tick);
+        }
+        let is_summarized = send_weekly_summaries(&tdlib, &state, now);
+        let timers = scheduler.pop_due(now);
+        if timers.is_empty() && !is_summarized {
+          return;
+        }
+        let mut fired_users = HashSet::new();
+        {
+          let alarms_map = state.alarms.borrow();
+          let users_map = state.users.borrow();
+          let contacts_map = state.contacts.borrow();
+          let mut sleeping_map = state.sleeping.borrow_mut();
+          for timer in timers.iter() {
+            let user_id = &timer.user_id;
+            fired_users.insert(*user_id);
+            let alarm_id = match timer.kind {
+              TimerKind::Alarm(alarm_id) => alarm_id,
+              TimerKind::Bedtime => {
+                if timer.due <= last_tick {
+                  println!(
+                    "[{}] Missed bedtime reminder of user {} due at {}",
+                    now, user_id, timer.due
+                  );
+                } else {
+                  send_bedtime_reminder(&tdlib, &state, *user_id, timer.due, now);
+                }
+                continue;
               }
-            };
-            if should_stop {
-              alarm.is_pending = false;
-              alarm.is_informing = 0;
-            }
-            if should_alarm {
-              if alarm.is_pending {
-                println!("[{}] Skipped alarm {} due to is pending", now, alarm);
+              TimerKind::Vacation => {
+                end_vacation(&tdlib, &state, *user_id, timer.due, now);
+                continue;
+              }
+              TimerKind::HeadsUp(alarm_id) => {
+                if timer.due <= last_tick {
+                  println!(
+                    "[{}] Missed heads-up of alarm {} of user {} due at {}",
+                    now, alarm_id, user_id, timer.due
+                  );
+                } else {
+                  send_heads_up(&tdlib, &state, *user_id, alarm_id, timer.due, now);
+                }
+                continue;
+              }
+              TimerKind::Countdown(id) => {
+                finish_countdown_phase(&tdlib, &state, *user_id, id, now);
                 continue;
               }
-              if alarm.is_disabled {
-                println!("[{}] Skipped alarm {} due to is disabled", now, alarm);
+              TimerKind::SharedSummary(id) => {
+                end_shared_round(&tdlib, &state, *user_id, id, timer.due, now);
                 continue;
               }
-              if alarm.is_onceoff {
-                println!("[{}] Skipped alarm {} due to is one off", now, alarm);
-                alarm.is_onceoff = false;
+              TimerKind::Sleep(chat_id) => {
+                end_sleep(&tdlib, &sleeping_map, *user_id, chat_id, timer.due, now);
                 continue;
               }
+            };
+            let mut alarms = match alarms_map.get(user_id) {
+              None => continue,
+              Some(user_alarms) => user_alarms.borrow_mut(),
+            };
+            // The call of another alarm of the user that is still going on.
+            let running_call = alarms
+              .iter()
+              .find(|alarm| alarm.id != alarm_id && alarm.ring.is_calling())
+              .map(|alarm| alarm.ring.call_id());
+            let alarm = match alarms.iter_mut().find(|alarm| alarm.id == alarm_id) {
+              None => continue,
+              Some(alarm) => alarm,
+            };
+            let is_following_up = match alarm.ring {
+              RingState::FollowingUp(_) => true,
+              _ => false,
+            };
+            if timer.due <= last_tick && !is_following_up {
+              let ring = alarm.ring.ring().cloned();
+              if alarm.ring.give_up() {
+                record_wake(&tdlib, &state, alarm, ring.as_ref(), true, now);
+                println!("[{}] Stopped alarm {} due to missed reschedule", now, alarm);
+              } else {
+                println!("[{}] Missed alarm {} due at {}", now, alarm, timer.due);
+              }
+              continue;
+            }
+            if alarm.ring.is_held(now) {
+              let call_id = alarm.ring.call_id().unwrap_or_default();
+              answer_call(&tdlib, alarm, now, &users_map, &mut sleeping_map, &state);
+              let req = DiscardCall::builder()
+                .is_disconnected(true)
+                .call_id(call_id)
+                .build();
+              tdlib.send(&req.to_json().expect("Bad JSON"));
+              continue;
+            }
+            if alarm.ring.is_calling() {
+              if alarm.ring.call_id().is_some() {
+                println!("[{}] Skipped alarm {} due to is pending", now, alarm);
+                continue;
+              }
+              alarm.ring.fail_call();
               println!(
-                "[{}] About to ring alarm {}, is informing: {}",
-                now, alarm, alarm.is_informing
+                "[{}] Failed call of alarm {} as it was never placed",
+                now, alarm
               );
-              alarm.is_pending = true;
-              if alarm.is_informing == 0 {
-                alarm.is_informing += 1;
-              }
-              alarm.reschedule = now + 300;
+            }
+            if is_following_up && !alarm.ring.is_follow_up_missed(now) {
+              send_follow_up(&tdlib, alarm, now);
+              continue;
+            }
+            if is_following_up {
               println!(
-                "[{}] Prospective next call of alarm {} scheduled at {}",
-                now, alarm, alarm.reschedule
+                "[{}] Ringing alarm {} again as user did not answer the follow-up",
+                now, alarm
               );
-              if alarm.title != "" {
-                let req = SendChatAction::builder()
-                  .chat_id(*user_id)
-                  .action(ChatAction::Typing(ChatActionTyping::builder().build()))
-                  .build();
-                tdlib.send(&req.to_json().expect("Bad JSON"));
-                let req = SendMessage::builder()
-                  .chat_id(*user_id)
-                  .input_message_content(build_plain_message(&alarm.title))
-                  .build();
-                tdlib.send(&req.to_json().expect("Bad JSON"));
-              }
-              let req = CreateCall::builder()
-                .user_id(*user_id)
-                .protocol(
-                  CallProtocol::builder()
-                    .udp_p2p(true)
-                    .udp_reflector(true)
-                    .min_layer(65)
-                    .max_layer(65),
-                )
+            } else if alarm.is_disabled {
+              println!("[{}] Skipped alarm {} due to is disabled", now, alarm);
+              continue;
+            } else if alarm.is_onceoff {
+              println!("[{}] Skipped alarm {} due to is one off", now, alarm);
+              alarm.is_onceoff = false;
+              continue;
+            }
+            println!(
+              "[{}] About to ring alarm {}, ring: {:?}",
+              now, alarm, alarm.ring
+            );
+            let channel = alarm.ring.fire(now, alarm.fallback.as_ref());
+            if !is_following_up && start_shared_round(&state, alarm, now) {
+              fired_users.insert(alarm.chat_id);
+            }
+            if let Some(contact) = contacts_map.get(user_id) {
+              if contact.contact_id != 0 && alarm.ring.needs_escalation(contact.after) {
+                let name = users_map
+                  .get(user_id)
+                  .map(|name| name.as_str())
+                  .unwrap_or("TA");
+                alert_contact(&tdlib, contact, name, alarm, now);
+              }
+            }
+            println!(
+              "[{}] Prospective next call of alarm {} scheduled at {}",
+              now,
+              alarm,
+              alarm.ring.retry_at().unwrap_or_default()
+            );
+            if let Some(channel) = channel.filter(|channel| *channel != RingChannel::Call) {
+              ring_fallback(&tdlib, alarm, channel, now);
+              continue;
+            }
+            if alarm.title != "" {
+              let req = SendChatAction::builder()
+                .chat_id(*user_id)
+                .action(ChatAction::Typing(ChatActionTyping::builder().build()))
+                .build();
+              tdlib.send(&req.to_json().expect("Bad JSON"));
+              let req = SendMessage::builder()
+                .chat_id(*user_id)
+                .input_message_content(build_plain_message(&alarm.title))
                 .build();
               tdlib.send(&req.to_json().expect("Bad JSON"));
             }
+            match running_call {
+              None => call_user(&tdlib, *user_id, Some(alarm.id)),
+              Some(call_id) => {
+                if let Some(call_id) = call_id {
+                  alarm.ring.bind_call(call_id);
+                }
+                println!("[{}] Alarm {} joined the running call", now, alarm);
+              }
+            }
           }
         }
+        for user_id in fired_users {
+          scheduler.reschedule(&state, user_id, now);
+        }
       }
       store.save().expect("Failed to save state");
     });
   })
 }
+
+fn send_bedtime_reminder(tdlib: &Client, state: &State, user_id: i64, due: i64, now: i64) {
+  let alarms_map = state.alarms.borrow();
+  let timezone_map = state.timezone.borrow();
+  let mut bedtime_map = state.bedtime.borrow_mut();
+  let vacation_map = state.vacation.borrow();
+  let vacation = vacation_map.get(&user_id);
+  let settings = match bedtime_map.get_mut(&user_id) {
+    None => return,
+    Some(settings) => settings,
+  };
+  let alarms = match alarms_map.get(&user_id) {
+    None => return,
+    Some(alarms) => alarms.borrow(),
+  };
+  let bedtime = match timezone_map.get(&user_id) {
+    Some(tz) => get_bedtime(
+      &alarms,
+      settings,
+      vacation,
+      &tz.parse::<Tz>().unwrap().timestamp(due, 0),
+    )
+    .map(|bedtime| (bedtime.wake.timestamp(), fmt_bedtime(&bedtime, settings))),
+    None => get_bedtime(
+      &alarms,
+      settings,
+      vacation,
+      &chrono::Local.timestamp(due, 0),
+    )
+    .map(|bedtime| (bedtime.wake.timestamp(), fmt_bedtime(&bedtime, settings))),
+  };
+  let (wake, text) = match bedtime {
+    None => return,
+    Some(bedtime) => bedtime,
+  };
+  if wake == settings.reminded_wake {
+    return;
+  }
+  settings.reminded_wake = wake;
+  let req = SendChatAction::builder()
+    .chat_id(user_id)
+    .action(ChatAction::Typing(ChatActionTyping::builder().build()))
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+  let req = SendMessage::builder()
+    .chat_id(user_id)
+    .input_message_content(build_plain_message(format!("该准备睡觉啦！{}", text)))
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+  println!(
+    "[{}] Sent bedtime reminder to user {} for wake alarm at {}",
+    now, user_id, wake
+  );
+}
+
+fn end_vacation(tdlib: &Client, state: &State, user_id: i64, due: i64, now: i64) {
+  let mut vacation_map = state.vacation.borrow_mut();
+  let vacation = match vacation_map.get(&user_id) {
+    Some(vacation) if vacation.until == due => vacation,
+    _ => return,
+  };
+  let text = match &vacation.tag {
+    None => String::from("假期结束啦，闹钟已恢复。"),
+    Some(tag) => format!("假期结束啦，#{} 闹钟已恢复。", tag),
+  };
+  vacation_map.remove(&user_id);
+  let req = SendMessage::builder()
+    .chat_id(user_id)
+    .input_message_content(build_plain_message(text))
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+  println!("[{}] Ended vacation of user {}", now, user_id);
+}
+
+/// Calls a user, for the alarm with `alarm_id` if it is one that rings.
+fn call_user(tdlib: &Client, user_id: i64, alarm_id: Option<i64>) {
+  let req = CreateCall::builder()
+    .user_id(user_id)
+    .protocol(
+      CallProtocol::builder()
+        .udp_p2p(true)
+        .udp_reflector(true)
+        .min_layer(65)
+        .max_layer(65),
+    )
+    .build();
+  tdlib.request(&req, Request::Call { user_id, alarm_id });
+}
+
+fn finish_countdown_phase(tdlib: &Client, state: &State, user_id: i64, id: i64, now: i64) {
+  let countdowns_map = state.countdowns.borrow();
+  let mut countdowns = match countdowns_map.get(&user_id) {
+    None => return,
+    Some(countdowns) => countdowns.borrow_mut(),
+  };
+  let index = match countdowns.iter().position(|countdown| countdown.id == id) {
+    None => return,
+    Some(index) => index,
+  };
+  let event = countdowns[index].advance();
+  let countdown = &countdowns[index];
+  let text = match (event, &countdown.pomodoro) {
+    (_, None) => match countdown.title.as_str() {
+      "" => format!("计时 [{}] 结束啦！", id),
+      title => format!("计时 [{}] {} 结束啦！", id, title),
+    },
+    (CountdownEvent::BreakStarted, Some(pomodoro)) => format!(
+      "第 {}/{} 轮专注结束，休息 {}。",
+      pomodoro.round,
+      pomodoro.rounds,
+      format_duration(pomodoro.rest)
+    ),
+    (CountdownEvent::WorkStarted, Some(pomodoro)) => format!(
+      "休息结束，开始第 {}/{} 轮专注，时长 {}。",
+      pomodoro.round,
+      pomodoro.rounds,
+      format_duration(pomodoro.work)
+    ),
+    (_, Some(_)) => format!(
+      "番茄钟 [{}] 结束啦！{}",
+      id,
+      fmt_pomodoro_summary(countdown, now)
+    ),
+  };
+  let chat_id = countdown.chat_id;
+  // A ringing alarm already keeps the user on the phone.
+  let is_alarm_calling = match state.alarms.borrow().get(&user_id) {
+    None => false,
+    Some(alarms) => alarms.borrow().iter().any(|alarm| alarm.ring.is_calling()),
+  };
+  let is_calling = countdown.is_calling && event.is_final() && !is_alarm_calling;
+  if event.is_final() {
+    countdowns.remove(index);
+  }
+  let req = SendMessage::builder()
+    .chat_id(chat_id)
+    .input_message_content(build_plain_message(text))
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+  if is_calling {
+    call_user(tdlib, user_id, None);
+  }
+  println!(
+    "[{}] Countdown {} of user {} reached {:?}",
+    now, id, user_id, event
+  );
+}
+
+fn send_heads_up(tdlib: &Client, state: &State, user_id: i64, alarm_id: i64, due: i64, now: i64) {
+  let alarms_map = state.alarms.borrow();
+  let timezone_map = state.timezone.borrow();
+  let mut alarms = match alarms_map.get(&user_id) {
+    None => return,
+    Some(alarms) => alarms.borrow_mut(),
+  };
+  let (id, alarm) = match alarms
+    .iter_mut()
+    .enumerate()
+    .find(|(_, alarm)| alarm.id == alarm_id)
+  {
+    None => return,
+    Some(found) => found,
+  };
+  let occurrence = due + alarm.heads_up;
+  if occurrence == alarm.heads_up_sent {
+    return;
+  }
+  alarm.heads_up_sent = occurrence;
+  let text = match timezone_map.get(&user_id) {
+    Some(tz) => fmt_heads_up(
+      alarm,
+      id,
+      &tz.parse::<Tz>().unwrap().timestamp(occurrence, 0),
+    ),
+    None => fmt_heads_up(alarm, id, &chrono::Local.timestamp(occurrence, 0)),
+  };
+  let req = SendMessage::builder()
+    .chat_id(alarm.chat_id)
+    .input_message_content(build_plain_message(text))
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+  println!(
+    "[{}] Sent heads-up for alarm {} ringing at {}",
+    now, alarm, occurrence
+  );
+}
+
+/// Picks a random voice note from the sound library at `SOUND_PATH`.
+fn pick_voice_note() -> Option<String> {
+  let dir = env::var("SOUND_PATH").ok()?;
+  let files: Vec<String> = std::fs::read_dir(dir)
+    .ok()?
+    .filter_map(|entry| entry.ok())
+    .map(|entry| entry.path())
+    .filter(|path| match path.extension().and_then(|ext| ext.to_str()) {
+      Some("ogg") | Some("oga") | Some("opus") => true,
+      _ => false,
+    })
+    .map(|path| path.to_string_lossy().into_owned())
+    .collect();
+  files.choose(&mut thread_rng()).cloned()
+}
+
+fn ring_fallback(tdlib: &Client, alarm: &mut Alarm, channel: RingChannel, now: i64) {
+  let text = fmt_fallback_ring(alarm);
+  let content = match (channel, pick_voice_note()) {
+    (RingChannel::VoiceNote, Some(path)) => InputMessageContent::InputMessageVoiceNote(
+      InputMessageVoiceNote::builder()
+        .voice_note(InputFile::Local(
+          InputFileLocal::builder().path(path).build(),
+        ))
+        .caption(FormattedText::builder().text(&text).build())
+        .build(),
+    ),
+    _ => build_plain_message(&text),
+  };
+  if channel == RingChannel::Pin {
+    alarm.ring.pin_next_message();
+  }
+  let req = SendMessage::builder()
+    .chat_id(alarm.user_id)
+    .input_message_content(content)
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+  if alarm.is_strict {
+    let challenge = issue_challenge(alarm, now);
+    let req = SendMessage::builder()
+      .chat_id(alarm.user_id)
+      .input_message_content(build_challenge_message(alarm, &challenge))
+      .build();
+    tdlib.send(&req.to_json().expect("Bad JSON"));
+  }
+  println!(
+    "[{}] Rang alarm {} on fallback channel {:?}",
+    now, alarm, channel
+  );
+}
+
+fn alert_contact(tdlib: &Client, contact: &EmergencyContact, name: &str, alarm: &Alarm, now: i64) {
+  let attempts = alarm
+    .ring
+    .ring()
+    .map(|ring| ring.attempts)
+    .unwrap_or_default();
+  let req = SendMessage::builder()
+    .chat_id(contact.contact_id)
+    .input_message_content(build_fmt_message(|f| {
+      f_emergency_alert(f, name, alarm.user_id, alarm, attempts)
+    }))
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+  if contact.is_calling {
+    call_user(tdlib, contact.contact_id, None);
+  }
+  println!(
+    "[{}] Alerted emergency contact {} of alarm {} after {} attempts",
+    now, contact.contact_id, alarm, attempts
+  );
+}
+
+fn unlock_user(tdlib: &Client, user_id: i64, sleeping_map: &mut HashMap<i64, RefCell<Vec<Sleep>>>) {
+  let user_sleeping = sleeping_map.get(&user_id);
+  if let None = user_sleeping {
+    return;
+  }
+  let user_sleeping = sleeping_map.get(&user_id).unwrap();
+  for sleep in user_sleeping.borrow().iter() {
+    lift_sleep(tdlib, user_id, sleep.chat_id);
+  }
+  sleeping_map.insert(user_id, RefCell::new(vec![]));
+}
+
+fn lift_sleep(tdlib: &Client, user_id: i64, chat_id: i64) {
+  let req = SetChatMemberStatus::builder()
+    .chat_id(chat_id)
+    .user_id(user_id)
+    .status(ChatMemberStatus::Member(
+      ChatMemberStatusMember::builder().build(),
+    ))
+    .build();
+  tdlib.request(
+    &req,
+    Request::ChatMember {
+      chat_id,
+      user_id,
+      is_muting: false,
+    },
+  );
+}
+
+/// Lifts the timed `#sleep!` of the user in `chat_id` that ends at `due`.
+fn end_sleep(
+  tdlib: &Client,
+  sleeping_map: &HashMap<i64, RefCell<Vec<Sleep>>>,
+  user_id: i64,
+  chat_id: i64,
+  due: i64,
+  now: i64,
+) {
+  let mut user_sleeping = match sleeping_map.get(&user_id) {
+    None => return,
+    Some(user_sleeping) => user_sleeping.borrow_mut(),
+  };
+  let len = user_sleeping.len();
+  user_sleeping.retain(|sleep| sleep.chat_id != chat_id || sleep.until != Some(due));
+  if user_sleeping.len() == len {
+    return;
+  }
+  lift_sleep(tdlib, user_id, chat_id);
+  println!(
+    "[{}] Lifted sleep of user {} in chat {} due at {}",
+    now, user_id, chat_id, due
+  );
+}
+
+fn send_help_message(
+  tdlib: &Client,
+  alarm: &mut Alarm,
+  now: i64,
+  is_discard: bool,
+  users_map: &HashMap<i64, String>,
+  user_id: i64,
+) {
+  let user_name = match users_map.get(&user_id) {
+    None => "他",
+    Some(name) => name,
+  };
+  if alarm.chat_id < 0 && alarm.ring.ask_for_help() {
+    let req = SendChatAction::builder()
+      .chat_id(alarm.chat_id)
+      .action(ChatAction::Typing(ChatActionTyping::builder().build()))
+      .build();
+    tdlib.send(&req.to_json().expect("Bad JSON"));
+    let req = SendMessage::builder()
+      .chat_id(alarm.chat_id)
+      .input_message_content(build_fmt_message(|f| {
+        f_help_alarm(f, user_name, user_id, is_discard)
+      }))
+      .build();
+    tdlib.send(&req.to_json().expect("Bad JSON"));
+    println!(
+      "[{}] Sent help message for alarm {} due to chat_id < 0, ring: {:?}",
+      now, alarm, alarm.ring
+    );
+  }
+}
+
+/// Handles an answered call of `alarm`: a non-strict alarm is fulfilled, a
+/// strict one gets its challenge.
+fn answer_call(
+  tdlib: &Client,
+  alarm: &mut Alarm,
+  now: i64,
+  users_map: &HashMap<i64, String>,
+  sleeping_map: &mut HashMap<i64, RefCell<Vec<Sleep>>>,
+  state: &State,
+) {
+  let user_id = alarm.user_id;
+  let ring = alarm.ring.ring().cloned();
+  if alarm.ring.answer(alarm.is_strict) {
+    unlock_user(tdlib, user_id, sleeping_map);
+    println!("[{}] Fulfilled alarm {} due to answering call", now, alarm);
+    record_wake(tdlib, state, alarm, ring.as_ref(), false, now);
+    start_follow_up(alarm, now);
+    return;
+  }
+  let challenge = issue_challenge(alarm, now);
+  let req = SendChatAction::builder()
+    .chat_id(user_id)
+    .action(ChatAction::Typing(ChatActionTyping::builder().build()))
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+  let req = SendMessage::builder()
+    .chat_id(user_id)
+    .input_message_content(build_challenge_message(alarm, &challenge))
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+  println!(
+    "[{}] Challenged user with {} in need of closing alarm {}",
+    now, challenge.answer, alarm
+  );
+  println!(
+    "[{}] Will alarm {} again due to unfulfilled strict call even it was answered",
+    now, alarm
+  );
+  send_help_message(tdlib, alarm, now, true, users_map, user_id);
+}
+
+/// Acts on a call from the user according to their `IncomingCall` setting.
+fn handle_incoming_call(tdlib: &Client, state: &State, clock: &dyn Clock, user_id: i64) {
+  let now = clock.timestamp();
+  let action = state
+    .incoming_call
+    .borrow()
+    .get(&user_id)
+    .cloned()
+    .unwrap_or(IncomingCall::Snooze);
+  if action == IncomingCall::Ignore {
+    return;
+  }
+  let alarms_map = state.alarms.borrow();
+  let no_alarms = RefCell::new(vec![]);
+  let mut alarms = alarms_map.get(&user_id).unwrap_or(&no_alarms).borrow_mut();
+  let mut lines = vec![];
+  // The calls still ringing the user, hung up once the alarms left them.
+  let mut call_ids = vec![];
+  for alarm in alarms.iter_mut().filter(|alarm| alarm.ring.is_ringing()) {
+    let title = match alarm.title.as_str() {
+      "" => String::default(),
+      title => format!(" {}", title),
+    };
+    if let Some(call_id) = alarm.ring.call_id() {
+      if !call_ids.contains(&call_id) {
+        call_ids.push(call_id);
+      }
+    }
+    if action == IncomingCall::Dismiss && !alarm.is_strict {
+      let ring = alarm.ring.ring().cloned();
+      alarm.ring.give_up();
+      record_wake(tdlib, state, alarm, ring.as_ref(), false, now);
+      unlock_user(tdlib, user_id, &mut state.sleeping.borrow_mut());
+      lines.push(format!("闹钟{}已关闭。", title));
+      println!("[{}] Fulfilled alarm {} due to incoming call", now, alarm);
+      start_follow_up(alarm, now);
+    } else {
+      alarm.ring.snooze(now + SNOOZE_INTERVAL);
+      lines.push(format!(
+        "闹钟{}已推迟 {}。",
+        title,
+        format_duration(SNOOZE_INTERVAL)
+      ));
+      println!("[{}] Snoozed alarm {} due to incoming call", now, alarm);
+    }
+  }
+  for call_id in call_ids {
+    let req = DiscardCall::builder()
+      .is_disconnected(true)
+      .call_id(call_id)
+      .build();
+    tdlib.send(&req.to_json().expect("Bad JSON"));
+    println!("[{}] Hung up call {} due to incoming call", now, call_id);
+  }
+  if lines.is_empty() {
+    let vacation_map = state.vacation.borrow();
+    let vacation = vacation_map.get(&user_id);
+    let quiet_hours_map = state.quiet_hours.borrow();
+    let quiet_hours = quiet_hours_map.get(&user_id);
+    let next_alarm = match state.timezone.borrow().get(&user_id) {
+      Some(tz) => {
+        let next_alarm = get_recent_schedule(
+          &alarms,
+          tz.parse::<Tz>().unwrap(),
+          0,
+          vacation,
+          quiet_hours,
+          clock,
+        );
+        next_alarm
+          .schedule()
+          .to_string()
+          .map(|time_str| format!("{} {}", time_str, next_alarm.alarm_title()))
+      }
+      None => {
+        let next_alarm =
+          get_recent_schedule(&alarms, chrono::Local, 0, vacation, quiet_hours, clock);
+        next_alarm
+          .schedule()
+          .to_string()
+          .map(|time_str| format!("{} {}", time_str, next_alarm.alarm_title()))
+      }
+    };
+    lines.push(match next_alarm {
+      Some(next_alarm) => format!("现在没有在响的闹钟。下次闹钟时间：{}", next_alarm),
+      None => String::from("现在没有在响的闹钟，也没有要响的闹钟了。"),
+    });
+  }
+  let req = SendMessage::builder()
+    .chat_id(user_id)
+    .input_message_content(build_plain_message(lines.join("\n")))
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+}
+
+/// Records the call placed for the alarm with `alarm_id`, and for the other
+/// alarms of the user that joined it before its ID was known. Returns whether
+/// any alarm was bound.
+fn bind_call(state: &State, clock: &dyn Clock, user_id: i64, alarm_id: i64, call_id: i64) -> bool {
+  let now = clock.timestamp();
+  let alarms_map = state.alarms.borrow();
+  let alarms = match alarms_map.get(&user_id) {
+    None => return false,
+    Some(alarms) => alarms,
+  };
+  let mut is_bound = false;
+  for alarm in alarms.borrow_mut().iter_mut() {
+    if alarm.ring.bind_call(call_id) {
+      is_bound = true;
+      println!("[{}] Bound call {} to alarm {}", now, call_id, alarm);
+    } else if alarm.id == alarm_id {
+      println!(
+        "[{}] Placed call {} for alarm {} that is no longer calling",
+        now, call_id, alarm
+      );
+    }
+  }
+  is_bound
+}
+
+/// Acts on a tagged request that failed: a call that could not be placed
+/// fails the alarm it was placed for and the alarms that joined it, and a
+/// mute that was refused takes the group off the user's sleeping list. Both are explained to the user.
+/// Returns the user whose state was changed.
+fn handle_error(
+  tdlib: &Client,
+  state: &State,
+  clock: &dyn Clock,
+  request: Request,
+  error: &Error,
+) -> Option<i64> {
+  let now = clock.timestamp();
+  println!(
+    "[{}] Request {:?} failed: {} {}",
+    now,
+    request,
+    error.code(),
+    error.message()
+  );
+  match request {
+    Request::Call {
+      user_id,
+      alarm_id: Some(alarm_id),
+    } => {
+      let alarms_map = state.alarms.borrow();
+      let mut alarms = alarms_map.get(&user_id)?.borrow_mut();
+      // Alarms that fired while the call was being placed joined it without
+      // knowing its ID, see `bind_call`, and fail with it.
+      let mut is_failed = false;
+      for alarm in alarms
+        .iter_mut()
+        .filter(|alarm| alarm.ring.is_calling() && alarm.ring.call_id().is_none())
+      {
+        alarm.ring.fail_call();
+        is_failed = true;
+        if alarm.id == alarm_id {
+          println!("[{}] Failed call of alarm {} as it was refused", now, alarm);
+        } else {
+          println!(
+            "[{}] Failed alarm {} as the call it joined was refused",
+            now, alarm
+          );
+        }
+      }
+      if !is_failed {
+        return None;
+      }
+      let text = match error.message().as_str() {
+        "USER_PRIVACY_RESTRICTED" => {
+          "你的隐私设置不允许我给你打电话。请在 设置 → 隐私与安全 → 通话 中允许我打电话给你。"
+        }
+        _ => "我没能给你打电话，稍后会再试。",
+      };
+      let req = SendMessage::builder()
+        .chat_id(user_id)
+        .input_message_content(build_plain_message(text))
+        .build();
+      tdlib.send(&req.to_json().expect("Bad JSON"));
+      Some(user_id)
+    }
+    Request::ChatMember {
+      chat_id,
+      user_id,
+      is_muting: true,
+    } => {
+      if let Some(user_sleeping) = state.sleeping.borrow().get(&user_id) {
+        user_sleeping
+          .borrow_mut()
+          .retain(|sleep| sleep.chat_id != chat_id);
+      }
+      let text = match error.message().as_str() {
+        "CHAT_ADMIN_REQUIRED" => "我不是这个群的管理员，没法禁言。请给我禁言权限后再 #sleep! 。",
+        _ => "禁言失败了，今晚只能靠你的自制力了。",
+      };
+      let req = SendMessage::builder()
+        .chat_id(chat_id)
+        .input_message_content(build_plain_message(text))
+        .build();
+      tdlib.send(&req.to_json().expect("Bad JSON"));
+      Some(user_id)
+    }
+    Request::Call { alarm_id: None, .. } | Request::ChatMember { .. } | Request::Message { .. } => {
+      None
+    }
+  }
+}
+
+/// Issues a new challenge of the alarm's kind and difficulty to its ring.
+fn issue_challenge(alarm: &mut Alarm, now: i64) -> Challenge {
+  let challenge = alarm
+    .challenge_kind
+    .challenge_type()
+    .issue(alarm.difficulty, &mut thread_rng());
+  alarm.ring.issue_challenge(challenge.clone(), now);
+  challenge
+}
+
+/// The message carrying a challenge of `alarm`: a distorted image of the
+/// prompt if the alarm asks for one and it can be drawn, text otherwise.
+fn build_challenge_message(alarm: &Alarm, challenge: &Challenge) -> InputMessageContent {
+  let text = || build_fmt_message(|f| f_strict_challenge(f, challenge));
+  let settings = match alarm.render {
+    None => return text(),
+    Some(settings) => settings,
+  };
+  let image = match render(&challenge.prompt, settings, &mut thread_rng()) {
+    None => return text(),
+    Some(image) => image,
+  };
+  // Each challenge gets its own file, removed by `remove_challenge_image`
+  // once the message is sent, so that a newer challenge cannot replace the
+  // image of one still uploading.
+  let path = format!(
+    "{}/challenge-{}-{}-{}.png",
+    env::var("DATA_PATH").expect("Unknown env DATA_PATH"),
+    alarm.user_id,
+    alarm.id,
+    thread_rng().gen::<u32>()
+  );
+  if let Err(err) = image.save(&path) {
+    eprintln!("Failed to save challenge image {}: {}", path, err);
+    return text();
+  }
+  InputMessageContent::InputMessagePhoto(
+    InputMessagePhoto::builder()
+      .photo(InputFile::Local(
+        InputFileLocal::builder().path(path).build(),
+      ))
+      .width(image.width as i64)
+      .height(image.height as i64)
+      .caption(
+        FormattedText::builder()
+          .text(&challenge.instruction)
+          .build(),
+      )
+      .build(),
+  )
+}
+
+/// Removes the challenge image written by `build_challenge_message` once the
+/// message carrying it was sent, or failed to.
+fn remove_challenge_image(message: &Message) {
+  let photo = match message.content() {
+    MessageContent::MessagePhoto(photo) => photo,
+    _ => return,
+  };
+  let dir = match env::var("DATA_PATH") {
+    Err(_) => return,
+    Ok(dir) => format!("{}/challenge-", dir),
+  };
+  for size in photo.photo().sizes() {
+    let path = size.photo().local().path();
+    if path.starts_with(&dir) {
+      if let Err(err) = std::fs::remove_file(path) {
+        eprintln!("Failed to remove challenge image {}: {}", path, err);
+      }
+    }
+  }
+}
+
+/// Whether some ringing alarm of the user waits for a location challenge.
+fn has_location_challenge(state: &State, user_id: i64) -> bool {
+  match state.alarms.borrow().get(&user_id) {
+    None => false,
+    Some(alarms) => alarms.borrow().iter().any(|alarm| {
+      alarm.ring.challenge().map(|challenge| challenge.kind) == Some(ChallengeKind::Location)
+    }),
+  }
+}
+
+/// Completes the location challenges of the user's ringing alarms if the
+/// live location is far enough from their home. Updates of a live location
+/// come often, so only the first one shared is told it is not far enough.
+/// Returns whether some alarm was fulfilled.
+fn check_location(
+  tdlib: &Client,
+  state: &State,
+  clock: &dyn Clock,
+  user_id: i64,
+  latitude: f64,
+  longitude: f64,
+  is_update: bool,
+) -> bool {
+  let now = clock.timestamp();
+  let home = match state.homes.borrow().get(&user_id) {
+    None => return false,
+    Some(home) => *home,
+  };
+  let alarms_map = state.alarms.borrow();
+  let alarms = match alarms_map.get(&user_id) {
+    None => return false,
+    Some(alarms) => alarms,
+  };
+  let distance = home.distance_to(latitude, longitude);
+  let mut is_fulfilled = false;
+  let mut is_challenged = false;
+  for alarm in alarms.borrow_mut().iter_mut() {
+    if alarm.ring.challenge().map(|challenge| challenge.kind) != Some(ChallengeKind::Location) {
+      continue;
+    }
+    is_challenged = true;
+    if distance <= home.distance {
+      continue;
+    }
+    let ring = alarm.ring.ring().cloned();
+    alarm.ring.complete_challenge();
+    record_wake(tdlib, state, alarm, ring.as_ref(), false, now);
+    is_fulfilled = true;
+    let req = SendMessage::builder()
+      .chat_id(user_id)
+      .input_message_content(build_plain_message(match alarm.title.as_str() {
+        "" => format!("你已离床 {:.0} 米，闹钟已关闭。", distance),
+        title => format!("你已离床 {:.0} 米，闹钟 {} 已关闭。", distance, title),
+      }))
+      .build();
+    tdlib.send(&req.to_json().expect("Bad JSON"));
+    println!(
+      "[{}] Fulfilled alarm {} due to sharing a location {:.0}m from home",
+      now, alarm, distance
+    );
+    start_follow_up(alarm, now);
+  }
+  if is_fulfilled {
+    unlock_user(tdlib, user_id, &mut state.sleeping.borrow_mut());
+  } else if is_challenged && !is_update {
+    let req = SendMessage::builder()
+      .chat_id(user_id)
+      .input_message_content(build_plain_message(format!(
+        "你离床只有 {:.0} 米，请走到 {:.0} 米以外，实时位置更新后闹钟就会关闭。",
+        distance, home.distance
+      )))
+      .build();
+    tdlib.send(&req.to_json().expect("Bad JSON"));
+  }
+  is_fulfilled
+}
+
+/// Records on the leaderboard of its group how `ring` of a group alarm ended,
+/// and for a shared alarm, how its member responded.
+fn record_wake(
+  tdlib: &Client,
+  state: &State,
+  alarm: &Alarm,
+  ring: Option<&Ring>,
+  is_failed: bool,
+  now: i64,
+) {
+  let ring = match ring {
+    Some(ring) if alarm.chat_id < 0 => ring,
+    _ => return,
+  };
+  let record = WakeRecord::new(alarm.user_id, ring, is_failed, now);
+  println!(
+    "[{}] Recorded {:?} of alarm {} in group {}",
+    now, record.outcome, alarm, alarm.chat_id
+  );
+  if let Some(shared_id) = alarm.shared_id {
+    if let Some(shared_alarms) = state.shared_alarms.borrow().get(&alarm.chat_id) {
+      let mut shared_alarms = shared_alarms.borrow_mut();
+      if let Some(shared) = shared_alarms
+        .iter_mut()
+        .find(|shared| shared.id == shared_id)
+      {
+        if shared.respond(alarm.user_id, record.outcome) {
+          send_shared_summary(tdlib, state, alarm.chat_id, shared, now);
+        }
+      }
+    }
+  }
+  state
+    .group_stats
+    .borrow_mut()
+    .entry(alarm.chat_id)
+    .or_default()
+    .record(record);
+}
+
+/// Tells the group of `shared` how its members responded to the current
+/// occurrence, which ends.
+fn send_shared_summary(
+  tdlib: &Client,
+  state: &State,
+  chat_id: i64,
+  shared: &mut SharedAlarm,
+  now: i64,
+) {
+  let responses = shared.end_round();
+  let req = SendMessage::builder()
+    .chat_id(chat_id)
+    .input_message_content(build_plain_message(fmt_shared_summary(
+      shared,
+      &responses,
+      &state.users.borrow(),
+    )))
+    .build();
+  tdlib.request(&req, Request::Message { chat_id });
+  println!(
+    "[{}] Sent summary of shared alarm {} to group {}",
+    now, shared.id, chat_id
+  );
+}
+
+/// Sends the summary of the shared alarm `id` of `chat_id` if it is still
+/// due at `due`, that is if not everyone responded before.
+fn end_shared_round(tdlib: &Client, state: &State, chat_id: i64, id: i64, due: i64, now: i64) {
+  let shared_alarms_map = state.shared_alarms.borrow();
+  let mut shared_alarms = match shared_alarms_map.get(&chat_id) {
+    None => return,
+    Some(shared_alarms) => shared_alarms.borrow_mut(),
+  };
+  match shared_alarms.iter_mut().find(|shared| shared.id == id) {
+    Some(shared) if shared.summary_due() == Some(due) => {
+      send_shared_summary(tdlib, state, chat_id, shared, now)
+    }
+    _ => (),
+  }
+}
+
+/// Starts an occurrence of the shared alarm `alarm` was joined from, as it
+/// rings for the first time. Returns whether one was started, in which case
+/// the group needs rescheduling.
+fn start_shared_round(state: &State, alarm: &Alarm, now: i64) -> bool {
+  let shared_id = match alarm.shared_id {
+    Some(shared_id) if alarm.ring.ring().map(|ring| ring.attempts) == Some(1) => shared_id,
+    _ => return false,
+  };
+  let shared_alarms_map = state.shared_alarms.borrow();
+  let mut shared_alarms = match shared_alarms_map.get(&alarm.chat_id) {
+    None => return false,
+    Some(shared_alarms) => shared_alarms.borrow_mut(),
+  };
+  let shared = match shared_alarms
+    .iter_mut()
+    .find(|shared| shared.id == shared_id)
+  {
+    None => return false,
+    Some(shared) => shared,
+  };
+  if !shared.start_round(now) {
+    return false;
+  }
+  println!(
+    "[{}] Started occurrence of shared alarm {} in group {}",
+    now, shared_id, alarm.chat_id
+  );
+  true
+}
+
+/// Unsubscribes the user from the shared alarms their removed alarms were
+/// joined from, once the command is done with the state.
+fn leave_removed_shared(store: &Store, scheduler: &Scheduler, removed: Vec<Alarm>) {
+  if removed.is_empty() {
+    return;
+  }
+  {
+    let state = store.state();
+    let shared_alarms_map = state.shared_alarms.borrow();
+    for alarm in removed.iter() {
+      let shared_id = match alarm.shared_id {
+        None => continue,
+        Some(shared_id) => shared_id,
+      };
+      if let Some(shared_alarms) = shared_alarms_map.get(&alarm.chat_id) {
+        let mut shared_alarms = shared_alarms.borrow_mut();
+        for shared in shared_alarms
+          .iter_mut()
+          .filter(|shared| shared.id == shared_id)
+        {
+          shared.unsubscribe(alarm.user_id);
+        }
+        shared_alarms.retain(|shared| !shared.subscribers.is_empty());
+      }
+      scheduler.touch(alarm.chat_id);
+    }
+  }
+  store.save().expect("Failed to save state");
+}
+
+/// Posts the weekly summary to the groups where it is due. Returns whether
+/// any was posted.
+fn send_weekly_summaries(tdlib: &Client, state: &State, now: i64) -> bool {
+  let users_map = state.users.borrow();
+  let mut group_stats_map = state.group_stats.borrow_mut();
+  let mut is_summarized = false;
+  for (chat_id, stats) in group_stats_map.iter_mut() {
+    if !stats.is_summary_due(now) {
+      continue;
+    }
+    let req = SendMessage::builder()
+      .chat_id(*chat_id)
+      .input_message_content(build_plain_message(fmt_standings(
+        "本周起床总结：",
+        &stats.standings(now - WEEK),
+        &users_map,
+      )))
+      .build();
+    tdlib.request(&req, Request::Message { chat_id: *chat_id });
+    stats.summary_sent = now;
+    is_summarized = true;
+    println!("[{}] Sent weekly summary to group {}", now, chat_id);
+  }
+  is_summarized
+}
+
+/// Records rings that a command gave up on as failed, once it is done with
+/// the state.
+fn record_given_up(tdlib: &Client, store: &Store, given_up: Vec<(Alarm, Ring)>, now: i64) {
+  if given_up.is_empty() {
+    return;
+  }
+  {
+    let state = store.state();
+    for (alarm, ring) in given_up.iter() {
+      record_wake(tdlib, &state, alarm, Some(ring), true, now);
+    }
+  }
+  store.save().expect("Failed to save state");
+}
+
+/// Starts the follow-up check of a fulfilled alarm, if it has one.
+fn start_follow_up(alarm: &mut Alarm, now: i64) {
+  if alarm.ring.follow_up(alarm.follow_up.as_ref(), now) {
+    println!(
+      "[{}] Will check whether user is still awake for alarm {} at {}",
+      now,
+      alarm,
+      alarm.ring.due_at().unwrap_or_default()
+    );
+  }
+}
+
+/// Asks the user of `alarm` whether they are still awake.
+fn send_follow_up(tdlib: &Client, alarm: &mut Alarm, now: i64) {
+  let window = alarm
+    .follow_up
+    .map(|follow_up| follow_up.window)
+    .unwrap_or(0);
+  if !alarm.ring.ask_follow_up(window, now) {
+    return;
+  }
+  let req = SendMessage::builder()
+    .chat_id(alarm.user_id)
+    .input_message_content(build_plain_message(fmt_follow_up(alarm, window)))
+    .build();
+  tdlib.send(&req.to_json().expect("Bad JSON"));
+  println!(
+    "[{}] Asked whether user is still awake for alarm {}",
+    now, alarm
+  );
+}
@@ -1,6 +1,26 @@
+// The crate is built with Rust 1.39, see the Dockerfiles, which has neither
+// `matches!`, `#[default]` on enum variants, `is_multiple_of` nor numeric
+// constants such as `i64::MAX`.
+#![allow(
+  clippy::match_like_matches_macro,
+  clippy::derivable_impls,
+  clippy::manual_is_multiple_of,
+  clippy::legacy_numeric_constants
+)]
+
 pub mod alarm;
+pub mod bedtime;
+pub mod challenge;
+pub mod client;
+pub mod clock;
 pub mod cmd;
+pub mod countdown;
 pub mod cron;
 pub mod fmt;
 pub mod handler;
+pub mod render;
+pub mod ring;
+pub mod scheduler;
+pub mod shared;
+pub mod stats;
 pub mod store;
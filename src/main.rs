@@ -1,9 +1,16 @@
 use hyper_bed_caller::handler::*;
 
 fn main() {
-  let (tdlib, store) = initialize_app();
-  let handler = start_handler(tdlib.clone(), store.clone());
-  let cron = start_cron(tdlib, store);
+  let (tdlib, store, clock, scheduler) = initialize_app();
+  let sender = start_sender(tdlib.clone(), clock.clone());
+  let handler = start_handler(
+    tdlib.clone(),
+    store.clone(),
+    clock.clone(),
+    scheduler.clone(),
+  );
+  let cron = start_cron(tdlib, store, clock, scheduler);
   handler.join().expect("Handler thread failed");
   cron.join().expect("Cron thread failed");
+  sender.join().expect("Sender thread failed");
 }
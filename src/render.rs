@@ -0,0 +1,211 @@
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::io;
+use std::path::Path;
+
+/// Pixels per dot of the bitmap font.
+const SCALE: i64 = 6;
+const GLYPH_WIDTH: i64 = 5;
+const GLYPH_HEIGHT: i64 = 7;
+const INK: u8 = 40;
+const PAPER: u8 = 245;
+
+/// How a challenge prompt is drawn when it is sent as an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RenderSettings {
+  /// Amplitude in pixels of the waves bending the text.
+  pub warp: u32,
+  /// Noise level in percent: share of speckled pixels, and the number of
+  /// lines struck through the text.
+  pub noise: u32,
+}
+
+impl Default for RenderSettings {
+  fn default() -> RenderSettings {
+    RenderSettings { warp: 6, noise: 8 }
+  }
+}
+
+/// An 8-bit grayscale image.
+#[derive(Debug, Clone)]
+pub struct Image {
+  pub width: u32,
+  pub height: u32,
+  pixels: Vec<u8>,
+}
+
+impl Image {
+  fn new(width: u32, height: u32) -> Image {
+    Image {
+      width,
+      height,
+      pixels: vec![PAPER; (width * height) as usize],
+    }
+  }
+  fn get(&self, x: i64, y: i64) -> u8 {
+    if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+      return PAPER;
+    }
+    self.pixels[(y * self.width as i64 + x) as usize]
+  }
+  fn set(&mut self, x: i64, y: i64, value: u8) {
+    if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+      return;
+    }
+    self.pixels[(y * self.width as i64 + x) as usize] = value;
+  }
+  fn line(&mut self, (x0, y0): (i64, i64), (x1, y1): (i64, i64), value: u8) {
+    let steps = (x1 - x0).abs().max((y1 - y0).abs()).max(1);
+    for i in 0..=steps {
+      let x = x0 + (x1 - x0) * i / steps;
+      let y = y0 + (y1 - y0) * i / steps;
+      self.set(x, y, value);
+      self.set(x, y + 1, value);
+    }
+  }
+  pub fn to_png(&self) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(((self.width + 1) * self.height) as usize);
+    for row in self.pixels.chunks(self.width as usize) {
+      raw.push(0);
+      raw.extend_from_slice(row);
+    }
+    let mut header = vec![];
+    header.extend_from_slice(&self.width.to_be_bytes());
+    header.extend_from_slice(&self.height.to_be_bytes());
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(
+      &mut png,
+      b"IDAT",
+      &miniz_oxide::deflate::compress_to_vec_zlib(&raw, 6),
+    );
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+  }
+  pub fn save<P>(&self, path: P) -> io::Result<()>
+  where
+    P: AsRef<Path>,
+  {
+    std::fs::write(path, self.to_png())
+  }
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+  png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+  let start = png.len();
+  png.extend_from_slice(kind);
+  png.extend_from_slice(data);
+  let crc = crc32(&png[start..]);
+  png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = 0xffff_ffffu32;
+  for byte in data {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = match crc & 1 {
+        1 => (crc >> 1) ^ 0xedb8_8320,
+        _ => crc >> 1,
+      };
+    }
+  }
+  !crc
+}
+
+/// Rows of the 5×7 bitmap of `c`, the leftmost dot in the highest bit.
+fn glyph(c: char) -> Option<[u8; 7]> {
+  let rows = match c {
+    '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+    '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+    '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+    '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+    '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+    '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+    '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+    '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+    '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+    '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+    'X' => [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11],
+    '+' => [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00],
+    '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+    '×' => [0x00, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x00],
+    ' ' => [0x00; 7],
+    _ => return None,
+  };
+  Some(rows)
+}
+
+/// Whether every character of `text` can be drawn by `render`.
+pub fn can_render(text: &str) -> bool {
+  !text.is_empty() && text.chars().all(|c| glyph(c).is_some())
+}
+
+/// Draws `text` with the bitmap font, every glyph shifted and sheared at
+/// random, then bends the whole image along two sine waves and covers it in
+/// speckles and lines, so that it is readable by eye but not easily by OCR.
+/// Returns `None` if some character cannot be drawn.
+pub fn render(text: &str, settings: RenderSettings, rng: &mut dyn RngCore) -> Option<Image> {
+  if !can_render(text) {
+    return None;
+  }
+  let warp = settings.warp as i64;
+  let margin = 2 * SCALE + warp;
+  let advance = (GLYPH_WIDTH + 2) * SCALE;
+  let count = text.chars().count() as i64;
+  let width = 2 * margin + count * advance;
+  let height = 2 * margin + (GLYPH_HEIGHT + 2) * SCALE;
+  let mut canvas = Image::new(width as u32, height as u32);
+  for (i, c) in text.chars().enumerate() {
+    let rows = glyph(c)?;
+    let left = margin + i as i64 * advance + rng.gen_range(-SCALE / 2, SCALE / 2 + 1);
+    let top = margin + SCALE + rng.gen_range(-SCALE, SCALE + 1);
+    let shear: f64 = rng.gen_range(-0.35, 0.35);
+    for (row, bits) in rows.iter().enumerate() {
+      let row = row as i64;
+      for col in 0..GLYPH_WIDTH {
+        if bits & (0x10 >> col) == 0 {
+          continue;
+        }
+        for dy in 0..SCALE {
+          let y = row * SCALE + dy;
+          let dx = (shear * (y - GLYPH_HEIGHT * SCALE / 2) as f64) as i64;
+          for x in 0..SCALE {
+            canvas.set(left + col * SCALE + x + dx, top + y, INK);
+          }
+        }
+      }
+    }
+  }
+  let mut image = Image::new(width as u32, height as u32);
+  let period_x: f64 = rng.gen_range(80.0, 160.0);
+  let period_y: f64 = rng.gen_range(40.0, 80.0);
+  let phase: f64 = rng.gen_range(0.0, 2.0 * PI);
+  for y in 0..height {
+    for x in 0..width {
+      let sx = x + (warp as f64 / 2.0 * (2.0 * PI * y as f64 / period_y + phase).sin()) as i64;
+      let sy = y + (warp as f64 * (2.0 * PI * x as f64 / period_x + phase).sin()) as i64;
+      image.set(x, y, canvas.get(sx, sy));
+    }
+  }
+  let speckles = width * height * settings.noise.min(100) as i64 / 100;
+  for _ in 0..speckles {
+    let (x, y) = (rng.gen_range(0, width), rng.gen_range(0, height));
+    let value = match image.get(x, y) {
+      INK => PAPER,
+      _ => INK,
+    };
+    image.set(x, y, value);
+  }
+  for _ in 0..settings.noise / 2 {
+    let from = (rng.gen_range(0, width / 4), rng.gen_range(0, height));
+    let to = (
+      rng.gen_range(width * 3 / 4, width),
+      rng.gen_range(0, height),
+    );
+    image.line(from, to, INK);
+  }
+  Some(image)
+}
@@ -0,0 +1,836 @@
+use crate::challenge::{deserialize_challenge, Challenge, ChallengeKind};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Seconds between two calls of an alarm that has not been fulfilled yet.
+pub const RETRY_INTERVAL: i64 = 300;
+/// Seconds a ringing alarm is put off when the user calls in to snooze it.
+pub const SNOOZE_INTERVAL: i64 = 600;
+
+/// How a ringing alarm reaches the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RingChannel {
+  /// A phone call, the only way to answer a ring.
+  Call,
+  /// A message with notification, sent again at every retry.
+  Message,
+  /// A message pinned in the private chat.
+  Pin,
+  /// A voice note picked from the local sound library.
+  VoiceNote,
+}
+
+impl Default for RingChannel {
+  fn default() -> RingChannel {
+    RingChannel::Call
+  }
+}
+
+impl FromStr for RingChannel {
+  type Err = &'static str;
+  fn from_str(input: &str) -> Result<RingChannel, &'static str> {
+    match input {
+      "call" => Ok(RingChannel::Call),
+      "message" => Ok(RingChannel::Message),
+      "pin" => Ok(RingChannel::Pin),
+      "voice" => Ok(RingChannel::VoiceNote),
+      _ => Err("Bad ring channel"),
+    }
+  }
+}
+
+/// Channels an alarm falls back to when calls fail, moving on to the next
+/// one after `after` failed attempts on the current one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fallback {
+  pub after: i64,
+  pub chain: Vec<RingChannel>,
+}
+
+impl Fallback {
+  pub fn new(after: i64) -> Fallback {
+    Fallback {
+      after,
+      chain: vec![
+        RingChannel::Message,
+        RingChannel::Pin,
+        RingChannel::VoiceNote,
+      ],
+    }
+  }
+  /// The channel that follows `channel`, if any.
+  pub fn next(&self, channel: RingChannel) -> Option<RingChannel> {
+    let index = match channel {
+      RingChannel::Call => 0,
+      channel => self.chain.iter().position(|c| *c == channel)? + 1,
+    };
+    self.chain.get(index).cloned()
+  }
+}
+
+/// Asks the user whether they are still awake `after` seconds after an
+/// alarm was fulfilled. Unless they answer within `window` seconds, the alarm
+/// rings again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FollowUp {
+  pub after: i64,
+  pub window: i64,
+}
+
+/// A follow-up check of a fulfilled alarm, see `FollowUp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FollowUpCheck {
+  /// When the user is asked.
+  pub check_at: i64,
+  /// Until when the user can answer, once they were asked.
+  pub deadline: Option<i64>,
+}
+
+/// Progress of an alarm that is ringing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ring {
+  /// When the alarm calls again if it is still not fulfilled.
+  pub retry_at: i64,
+  /// Whether the group of a group alarm has been asked for help.
+  pub is_helped: bool,
+  /// The strict challenge, once one was issued.
+  #[serde(default, deserialize_with = "deserialize_challenge")]
+  pub challenge: Option<Challenge>,
+  /// The TDLib call placed for this ring, once TDLib told its ID. Only set
+  /// while `Calling`.
+  #[serde(default)]
+  pub call_id: Option<i64>,
+  /// The channel the alarm rings on, see `Fallback`.
+  #[serde(default)]
+  pub channel: RingChannel,
+  /// Failed attempts on `channel` so far: calls that errored out or were
+  /// never placed, and every attempt on a fallback channel.
+  #[serde(default)]
+  pub failures: i64,
+  /// Whether the next message sent to the user is to be pinned.
+  #[serde(default)]
+  pub is_pinning: bool,
+  /// Until when the answered call must stay connected, for alarms with a
+  /// minimum call duration.
+  #[serde(default)]
+  pub held_until: Option<i64>,
+  /// How many times the alarm fired in this ring.
+  #[serde(default)]
+  pub attempts: i64,
+  /// Whether the emergency contact asked to stop being alerted.
+  #[serde(default)]
+  pub is_acknowledged: bool,
+  /// Wrong answers to the challenges of this ring.
+  #[serde(default)]
+  pub wrong_answers: u32,
+  /// What happened in this ring so far, oldest first.
+  #[serde(default)]
+  pub history: Vec<RingRecord>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RingEvent {
+  /// The alarm rang on this channel.
+  Fired(RingChannel),
+  /// A challenge of this kind was issued, or regenerated.
+  ChallengeIssued(ChallengeKind),
+  /// The challenge was answered wrong.
+  WrongAnswer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RingRecord {
+  pub at: i64,
+  pub event: RingEvent,
+}
+
+/// Where an alarm is in its ring lifecycle.
+///
+/// ```text
+///          fire                answer (non-strict)
+///   Idle ───────▶ Calling ──────────────────────────▶ Idle
+///                  │   ▲
+///   discard, error,│   │fire
+///   answer (strict)▼   │
+///                 Waiting ───────────────────────────▶ Idle
+///                          challenge solved, give-up
+/// ```
+///
+/// A strict alarm that is answered goes on waiting, and calls again until the
+/// challenge issued on answering is solved. Giving up, when the alarm missed
+/// its retry or was turned off by hand, brings any state back to `Idle`.
+///
+/// An alarm with a minimum call duration is held `Calling` once answered,
+/// and only counts as answered when the call is still connected at the end
+/// of the hold. A call hung up earlier is discarded like an unanswered one.
+///
+/// Calling the bot snoozes a ringing alarm, or dismisses it if the user chose
+/// so and it is not strict, see `IncomingCall`.
+///
+/// Only a call can be answered. When calls keep failing, the alarm falls back
+/// to other channels, see `Fallback`. There it waits for the user to reply
+/// instead, or to solve the challenge if it is strict.
+///
+/// An alarm with a follow-up is not `Idle` right away once fulfilled, but
+/// `FollowingUp` until the user answers the check. If they do not, it fires
+/// again as if from `Idle`.
+///
+/// A user only ever has one call going on. When an alarm fires while another
+/// alarm of the same user is `Calling`, whether in the same tick or not, it
+/// joins that call instead of placing its own: it records the same call ID,
+/// and answering, discarding or failing the call moves every alarm on it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RingState {
+  /// Not ringing.
+  Idle,
+  /// A call has been placed and has not ended yet.
+  Calling(Ring),
+  /// The last call ended without fulfilling the alarm.
+  Waiting(Ring),
+  /// Fulfilled, and about to check whether the user is still awake.
+  FollowingUp(FollowUpCheck),
+}
+
+impl Default for RingState {
+  fn default() -> RingState {
+    RingState::Idle
+  }
+}
+
+impl RingState {
+  pub fn is_ringing(&self) -> bool {
+    match self {
+      RingState::Calling(_) | RingState::Waiting(_) => true,
+      _ => false,
+    }
+  }
+  pub fn is_calling(&self) -> bool {
+    match self {
+      RingState::Calling(_) => true,
+      _ => false,
+    }
+  }
+  pub fn ring(&self) -> Option<&Ring> {
+    match self {
+      RingState::Calling(ring) | RingState::Waiting(ring) => Some(ring),
+      _ => None,
+    }
+  }
+  fn ring_mut(&mut self) -> Option<&mut Ring> {
+    match self {
+      RingState::Calling(ring) | RingState::Waiting(ring) => Some(ring),
+      _ => None,
+    }
+  }
+  pub fn retry_at(&self) -> Option<i64> {
+    self.ring().map(|ring| ring.retry_at)
+  }
+  /// When the scheduler should next look at the alarm: the end of the hold
+  /// if the call is held, the check or its deadline if following up, the
+  /// retry otherwise.
+  pub fn due_at(&self) -> Option<i64> {
+    match self {
+      RingState::Calling(Ring {
+        held_until: Some(held_until),
+        ..
+      }) => Some(*held_until),
+      RingState::FollowingUp(check) => Some(check.deadline.unwrap_or(check.check_at)),
+      _ => self.retry_at(),
+    }
+  }
+  pub fn challenge(&self) -> Option<&Challenge> {
+    self.ring().and_then(|ring| ring.challenge.as_ref())
+  }
+  /// Cron fire: `Idle` or `Waiting` becomes `Calling`, due to call again
+  /// after `RETRY_INTERVAL`. Once `fallback` allows it, the alarm moves on to
+  /// the next channel; rings on other channels than calls go on `Waiting`
+  /// right away. Returns the channel to ring on, or `None` and changes
+  /// nothing while a call is still going on.
+  pub fn fire(&mut self, now: i64, fallback: Option<&Fallback>) -> Option<RingChannel> {
+    let mut ring = match self {
+      RingState::Calling(_) => return None,
+      RingState::Idle | RingState::FollowingUp(_) => Ring::default(),
+      RingState::Waiting(ring) => ring.clone(),
+    };
+    if let Some(fallback) = fallback {
+      if ring.failures >= fallback.after {
+        if let Some(channel) = fallback.next(ring.channel) {
+          ring.channel = channel;
+          ring.failures = 0;
+        }
+      }
+    }
+    ring.retry_at = now + RETRY_INTERVAL;
+    ring.call_id = None;
+    ring.held_until = None;
+    ring.attempts += 1;
+    let channel = ring.channel;
+    ring.history.push(RingRecord {
+      at: now,
+      event: RingEvent::Fired(channel),
+    });
+    *self = match channel {
+      RingChannel::Call => RingState::Calling(ring),
+      _ => {
+        ring.failures += 1;
+        RingState::Waiting(ring)
+      }
+    };
+    Some(channel)
+  }
+  pub fn channel(&self) -> RingChannel {
+    self.ring().map(|ring| ring.channel).unwrap_or_default()
+  }
+  /// Call placed: records the ID of the call of a `Calling` alarm that does
+  /// not know it yet. Returns whether it did.
+  pub fn bind_call(&mut self, call_id: i64) -> bool {
+    match self {
+      RingState::Calling(ring) if ring.call_id.is_none() => {
+        ring.call_id = Some(call_id);
+        true
+      }
+      _ => false,
+    }
+  }
+  pub fn call_id(&self) -> Option<i64> {
+    match self {
+      RingState::Calling(ring) => ring.call_id,
+      _ => None,
+    }
+  }
+  pub fn is_on_call(&self, call_id: i64) -> bool {
+    self.call_id() == Some(call_id)
+  }
+  /// Call connected for an alarm with a minimum call duration: `Calling`
+  /// is held until `until`. Returns whether it was calling.
+  pub fn hold(&mut self, until: i64) -> bool {
+    match self {
+      RingState::Calling(ring) => {
+        ring.held_until = Some(until);
+        true
+      }
+      _ => false,
+    }
+  }
+  /// Whether the call has stayed connected for the whole hold.
+  pub fn is_held(&self, now: i64) -> bool {
+    match self {
+      RingState::Calling(Ring {
+        held_until: Some(held_until),
+        ..
+      }) => *held_until <= now,
+      _ => false,
+    }
+  }
+  /// Call answered: a non-strict alarm is fulfilled and becomes `Idle`, a
+  /// strict one goes on `Waiting` for its challenge. Returns whether the
+  /// alarm was fulfilled.
+  pub fn answer(&mut self, is_strict: bool) -> bool {
+    let ring = match self {
+      RingState::Calling(ring) => ring.clone(),
+      _ => return false,
+    };
+    if is_strict {
+      *self = RingState::Waiting(Ring {
+        call_id: None,
+        ..ring
+      });
+      return false;
+    }
+    *self = RingState::Idle;
+    true
+  }
+  /// Call discarded or failed: `Calling` becomes `Waiting`. Returns whether
+  /// a call was going on.
+  pub fn hang_up(&mut self) -> bool {
+    let ring = match self {
+      RingState::Calling(ring) => ring.clone(),
+      _ => return false,
+    };
+    *self = RingState::Waiting(Ring {
+      call_id: None,
+      held_until: None,
+      ..ring
+    });
+    true
+  }
+  /// Call failed, because it errored out or was never placed: `Calling`
+  /// becomes `Waiting` and the failure counts towards the fallback. Returns
+  /// whether a call was going on.
+  pub fn fail_call(&mut self) -> bool {
+    let ring = match self {
+      RingState::Calling(ring) => ring.clone(),
+      _ => return false,
+    };
+    *self = RingState::Waiting(Ring {
+      call_id: None,
+      held_until: None,
+      failures: ring.failures + 1,
+      ..ring
+    });
+    true
+  }
+  /// Snoozed by an incoming call: a ringing alarm goes on `Waiting` until
+  /// `until`. Returns whether it was ringing.
+  pub fn snooze(&mut self, until: i64) -> bool {
+    let ring = match self.ring() {
+      None => return false,
+      Some(ring) => ring.clone(),
+    };
+    *self = RingState::Waiting(Ring {
+      retry_at: until,
+      call_id: None,
+      held_until: None,
+      ..ring
+    });
+    true
+  }
+  /// Reply received: a non-strict alarm ringing on a fallback channel
+  /// becomes `Idle`. Returns whether it did.
+  pub fn acknowledge(&mut self) -> bool {
+    match self {
+      RingState::Waiting(ring) if ring.channel != RingChannel::Call => {
+        *self = RingState::Idle;
+        true
+      }
+      _ => false,
+    }
+  }
+  /// Whether the emergency contact is to be alerted, once more than `after`
+  /// attempts went unanswered and until they acknowledge.
+  pub fn needs_escalation(&self, after: i64) -> bool {
+    match self.ring() {
+      Some(ring) => ring.attempts > after && !ring.is_acknowledged,
+      None => false,
+    }
+  }
+  /// Escalation acknowledged: the emergency contact is not alerted again in
+  /// this ring. Returns whether the alarm was ringing.
+  pub fn acknowledge_escalation(&mut self) -> bool {
+    match self.ring_mut() {
+      Some(ring) => {
+        ring.is_acknowledged = true;
+        true
+      }
+      None => false,
+    }
+  }
+  /// Marks the next message sent to the user for pinning.
+  pub fn pin_next_message(&mut self) {
+    if let Some(ring) = self.ring_mut() {
+      ring.is_pinning = true;
+    }
+  }
+  /// Returns whether the message just sent is to be pinned, and clears it.
+  pub fn take_pin(&mut self) -> bool {
+    match self.ring_mut() {
+      Some(ring) if ring.is_pinning => {
+        ring.is_pinning = false;
+        true
+      }
+      _ => false,
+    }
+  }
+  /// Challenge issued: records it, replacing any earlier challenge. Does
+  /// nothing unless the alarm is ringing.
+  pub fn issue_challenge(&mut self, challenge: Challenge, now: i64) {
+    if let Some(ring) = self.ring_mut() {
+      ring.history.push(RingRecord {
+        at: now,
+        event: RingEvent::ChallengeIssued(challenge.kind),
+      });
+      ring.challenge = Some(challenge);
+    }
+  }
+  /// Challenge answered wrong: counts it. Returns the wrong answers of this
+  /// ring so far, 0 if there is no challenge to answer.
+  pub fn fail_challenge(&mut self, now: i64) -> u32 {
+    match self.ring_mut() {
+      Some(ring) if ring.challenge.is_some() => {
+        ring.wrong_answers += 1;
+        ring.history.push(RingRecord {
+          at: now,
+          event: RingEvent::WrongAnswer,
+        });
+        ring.wrong_answers
+      }
+      _ => 0,
+    }
+  }
+  /// Challenge solved: a ringing alarm whose challenge is answered with
+  /// `text` becomes `Idle`. Returns whether it did.
+  pub fn solve(&mut self, text: &str) -> bool {
+    match self.challenge() {
+      Some(challenge) if challenge.check(text) => self.complete_challenge(),
+      _ => false,
+    }
+  }
+  /// Challenge completed by other means than text, such as a location: a
+  /// ringing alarm with a challenge becomes `Idle`. Returns whether it did.
+  pub fn complete_challenge(&mut self) -> bool {
+    if self.challenge().is_none() {
+      return false;
+    }
+    *self = RingState::Idle;
+    true
+  }
+  /// Fulfilled: an `Idle` alarm with a follow-up starts `FollowingUp`.
+  /// Returns whether it did.
+  pub fn follow_up(&mut self, follow_up: Option<&FollowUp>, now: i64) -> bool {
+    match (&self, follow_up) {
+      (RingState::Idle, Some(follow_up)) => {
+        *self = RingState::FollowingUp(FollowUpCheck {
+          check_at: now + follow_up.after,
+          deadline: None,
+        });
+        true
+      }
+      _ => false,
+    }
+  }
+  /// Check due: the user is asked and has `window` seconds to answer.
+  /// Returns whether the check was due.
+  pub fn ask_follow_up(&mut self, window: i64, now: i64) -> bool {
+    match self {
+      RingState::FollowingUp(check) if check.deadline.is_none() => {
+        check.deadline = Some(now + window);
+        true
+      }
+      _ => false,
+    }
+  }
+  /// Whether the user was asked and the deadline to answer has passed.
+  pub fn is_follow_up_missed(&self, now: i64) -> bool {
+    match self {
+      RingState::FollowingUp(FollowUpCheck {
+        deadline: Some(deadline),
+        ..
+      }) => *deadline <= now,
+      _ => false,
+    }
+  }
+  /// Check answered: `FollowingUp` becomes `Idle` once the user was asked.
+  /// Returns whether it did.
+  pub fn confirm_awake(&mut self) -> bool {
+    match self {
+      RingState::FollowingUp(FollowUpCheck {
+        deadline: Some(_), ..
+      }) => {
+        *self = RingState::Idle;
+        true
+      }
+      _ => false,
+    }
+  }
+  /// Give-up: any state becomes `Idle`. Returns whether the alarm was ringing.
+  pub fn give_up(&mut self) -> bool {
+    let was_ringing = self.is_ringing();
+    *self = RingState::Idle;
+    was_ringing
+  }
+  /// Marks the group as asked for help. Returns true only the first time in
+  /// a ring, when the help message should be sent.
+  pub fn ask_for_help(&mut self) -> bool {
+    match self.ring_mut() {
+      Some(ring) if !ring.is_helped => {
+        ring.is_helped = true;
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::Alarm;
+
+  fn calling() -> RingState {
+    let mut state = RingState::Idle;
+    state.fire(100, None);
+    state
+  }
+
+  fn waiting() -> RingState {
+    let mut state = calling();
+    state.hang_up();
+    state
+  }
+
+  fn challenged() -> RingState {
+    let mut state = waiting();
+    state.issue_challenge(Challenge::legacy("一二三"), 110);
+    state
+  }
+
+  #[test]
+  fn fire_from_idle_calls() {
+    let mut state = RingState::Idle;
+    assert_eq!(state.fire(100, None), Some(RingChannel::Call));
+    assert!(state.is_calling());
+    let ring = state.ring().unwrap();
+    assert_eq!(ring.retry_at, 100 + RETRY_INTERVAL);
+    assert_eq!(ring.attempts, 1);
+    assert_eq!(
+      ring.history,
+      vec![RingRecord {
+        at: 100,
+        event: RingEvent::Fired(RingChannel::Call),
+      }]
+    );
+  }
+
+  #[test]
+  fn fire_while_calling_does_nothing() {
+    let mut state = calling();
+    let before = state.clone();
+    assert_eq!(state.fire(200, None), None);
+    assert_eq!(state, before);
+  }
+
+  #[test]
+  fn fire_from_waiting_keeps_the_ring() {
+    let mut state = waiting();
+    state.ask_for_help();
+    assert_eq!(state.fire(400, None), Some(RingChannel::Call));
+    let ring = state.ring().unwrap();
+    assert!(ring.is_helped);
+    assert_eq!(ring.attempts, 2);
+    assert_eq!(ring.retry_at, 400 + RETRY_INTERVAL);
+  }
+
+  #[test]
+  fn fire_falls_back_after_failed_calls() {
+    let fallback = Fallback::new(1);
+    let mut state = RingState::Idle;
+    state.fire(100, Some(&fallback));
+    assert!(state.fail_call());
+    assert_eq!(state.fire(400, Some(&fallback)), Some(RingChannel::Message));
+    assert!(!state.is_calling());
+    assert!(state.is_ringing());
+    assert_eq!(state.channel(), RingChannel::Message);
+    assert_eq!(state.fire(700, Some(&fallback)), Some(RingChannel::Pin));
+  }
+
+  #[test]
+  fn bind_call_only_once() {
+    let mut state = calling();
+    assert!(state.bind_call(7));
+    assert!(!state.bind_call(8));
+    assert!(state.is_on_call(7));
+    assert!(!waiting().bind_call(7));
+  }
+
+  #[test]
+  fn answer_fulfills_non_strict() {
+    let mut state = calling();
+    assert!(state.answer(false));
+    assert_eq!(state, RingState::Idle);
+  }
+
+  #[test]
+  fn answer_keeps_strict_waiting() {
+    let mut state = calling();
+    state.bind_call(7);
+    assert!(!state.answer(true));
+    assert!(state.is_ringing());
+    assert!(!state.is_calling());
+    assert_eq!(state.call_id(), None);
+  }
+
+  #[test]
+  fn answer_needs_a_call() {
+    let mut state = waiting();
+    assert!(!state.answer(false));
+    assert!(state.is_ringing());
+  }
+
+  #[test]
+  fn hang_up_waits_without_failure() {
+    let mut state = calling();
+    state.bind_call(7);
+    state.hold(150);
+    assert!(state.hang_up());
+    let ring = state.ring().unwrap();
+    assert_eq!(ring.call_id, None);
+    assert_eq!(ring.held_until, None);
+    assert_eq!(ring.failures, 0);
+    assert!(!state.hang_up());
+  }
+
+  #[test]
+  fn fail_call_counts_failure() {
+    let mut state = calling();
+    assert!(state.fail_call());
+    assert!(!state.is_calling());
+    assert_eq!(state.ring().unwrap().failures, 1);
+    assert!(!state.fail_call());
+  }
+
+  #[test]
+  fn hold_until_due() {
+    let mut state = calling();
+    assert!(state.hold(160));
+    assert_eq!(state.due_at(), Some(160));
+    assert!(!state.is_held(159));
+    assert!(state.is_held(160));
+    assert!(!waiting().hold(160));
+  }
+
+  #[test]
+  fn snooze_waits_until() {
+    let mut state = calling();
+    assert!(state.snooze(900));
+    assert!(!state.is_calling());
+    assert_eq!(state.retry_at(), Some(900));
+    assert!(!RingState::Idle.snooze(900));
+  }
+
+  #[test]
+  fn acknowledge_only_fallback_channels() {
+    let mut state = waiting();
+    assert!(!state.acknowledge());
+    let fallback = Fallback::new(0);
+    let mut state = RingState::Idle;
+    state.fire(100, Some(&fallback));
+    assert!(state.acknowledge());
+    assert_eq!(state, RingState::Idle);
+  }
+
+  #[test]
+  fn escalation_until_acknowledged() {
+    let mut state = waiting();
+    state.fire(400, None);
+    assert!(state.needs_escalation(1));
+    assert!(!state.needs_escalation(2));
+    assert!(state.acknowledge_escalation());
+    assert!(!state.needs_escalation(1));
+  }
+
+  #[test]
+  fn pin_is_taken_once() {
+    let mut state = waiting();
+    state.pin_next_message();
+    assert!(state.take_pin());
+    assert!(!state.take_pin());
+  }
+
+  #[test]
+  fn challenge_solved() {
+    let mut state = challenged();
+    assert_eq!(state.fail_challenge(120), 1);
+    assert!(!state.solve("一二"));
+    assert!(state.solve(" 一二三 "));
+    assert_eq!(state, RingState::Idle);
+  }
+
+  #[test]
+  fn challenge_needs_ringing() {
+    let mut state = RingState::Idle;
+    state.issue_challenge(Challenge::legacy("一"), 100);
+    assert_eq!(state.challenge(), None);
+    assert_eq!(state.fail_challenge(100), 0);
+    assert!(!state.complete_challenge());
+    assert!(!waiting().complete_challenge());
+    assert!(challenged().complete_challenge());
+  }
+
+  #[test]
+  fn follow_up_rings_again_when_missed() {
+    let follow_up = FollowUp {
+      after: 600,
+      window: 300,
+    };
+    let mut state = RingState::Idle;
+    assert!(!state.follow_up(None, 100));
+    assert!(state.follow_up(Some(&follow_up), 100));
+    assert_eq!(state.due_at(), Some(700));
+    assert!(!state.confirm_awake());
+    assert!(state.ask_follow_up(300, 700));
+    assert!(!state.ask_follow_up(300, 700));
+    assert_eq!(state.due_at(), Some(1000));
+    assert!(!state.is_follow_up_missed(999));
+    assert!(state.is_follow_up_missed(1000));
+    assert!(!state.is_ringing());
+    assert_eq!(state.fire(1000, None), Some(RingChannel::Call));
+    assert_eq!(state.ring().unwrap().attempts, 1);
+  }
+
+  #[test]
+  fn follow_up_confirmed() {
+    let mut state = RingState::FollowingUp(FollowUpCheck {
+      check_at: 700,
+      deadline: Some(1000),
+    });
+    assert!(state.confirm_awake());
+    assert_eq!(state, RingState::Idle);
+  }
+
+  #[test]
+  fn give_up_from_any_state() {
+    for mut state in vec![
+      calling(),
+      waiting(),
+      RingState::FollowingUp(FollowUpCheck {
+        check_at: 700,
+        deadline: None,
+      }),
+      RingState::Idle,
+    ] {
+      let was_ringing = state.is_ringing();
+      assert_eq!(state.give_up(), was_ringing);
+      assert_eq!(state, RingState::Idle);
+    }
+  }
+
+  #[test]
+  fn ask_for_help_once() {
+    let mut state = waiting();
+    assert!(state.ask_for_help());
+    assert!(!state.ask_for_help());
+    assert!(!RingState::Idle.ask_for_help());
+  }
+
+  #[test]
+  fn legacy_save_resumes_waiting() {
+    let mut alarm: Alarm = serde_json::from_str(
+      r#"{
+        "user_id": 1, "chat_id": 1, "cron": "0 0 7 * * *", "title": "",
+        "is_strict": true, "is_onceoff": false, "is_disabled": false,
+        "is_informing": 3, "reschedule": 500, "strict_challenge": "一二三"
+      }"#,
+    )
+    .unwrap();
+    assert_eq!(alarm.ring, RingState::Idle);
+    alarm.migrate_ring();
+    let ring = alarm.ring.ring().unwrap();
+    assert!(!alarm.ring.is_calling());
+    assert_eq!(ring.retry_at, 500);
+    assert!(ring.is_helped);
+    assert_eq!(ring.challenge, Some(Challenge::legacy("一二三")));
+  }
+
+  #[test]
+  fn legacy_save_not_ringing_stays_idle() {
+    let mut alarm: Alarm = serde_json::from_str(
+      r#"{
+        "user_id": 1, "chat_id": 1, "cron": "0 0 7 * * *", "title": "",
+        "is_strict": false, "is_onceoff": false, "is_disabled": false,
+        "is_informing": 0, "reschedule": 0, "strict_challenge": ""
+      }"#,
+    )
+    .unwrap();
+    alarm.migrate_ring();
+    assert_eq!(alarm.ring, RingState::Idle);
+  }
+
+  #[test]
+  fn legacy_challenge_in_ring() {
+    let state: RingState = serde_json::from_str(
+      r#"{"Waiting": {"retry_at": 500, "is_helped": false, "challenge": "一二"}}"#,
+    )
+    .unwrap();
+    assert_eq!(state.challenge(), Some(&Challenge::legacy("一二")));
+  }
+}
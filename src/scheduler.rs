@@ -0,0 +1,348 @@
+use crate::alarm::{get_due_timestamp, get_heads_up_occurrence};
+use crate::bedtime::get_bedtime;
+use crate::clock::Clock;
+use crate::store::State;
+use chrono::TimeZone;
+use chrono_tz::Tz;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TimerKind {
+  /// The alarm with this ID.
+  Alarm(i64),
+  /// The heads-up before the alarm with this ID.
+  HeadsUp(i64),
+  /// The bedtime reminder before the user's next wake alarm.
+  Bedtime,
+  /// The end of the user's vacation.
+  Vacation,
+  /// The end of the current phase of the countdown with this ID.
+  Countdown(i64),
+  /// The end of the user's timed `#sleep!` in the chat with this ID.
+  Sleep(i64),
+  /// The summary of the shared alarm with this ID. These entries are queued
+  /// under the chat ID of the group instead of a user ID.
+  SharedSummary(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timer {
+  pub due: i64,
+  pub user_id: i64,
+  pub kind: TimerKind,
+  generation: u64,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+  timers: BinaryHeap<Reverse<Timer>>,
+  generations: HashMap<i64, u64>,
+  /// Number of live entries of each user still in `timers`.
+  live: HashMap<i64, usize>,
+  /// Sum of `live`.
+  live_len: usize,
+  dirty: HashSet<i64>,
+}
+
+impl Queue {
+  fn is_current(&self, timer: &Timer) -> bool {
+    self.generations.get(&timer.user_id) == Some(&timer.generation)
+  }
+  /// Drops stale entries until a live one is at the top.
+  fn drop_stale(&mut self) {
+    while let Some(Reverse(timer)) = self.timers.peek() {
+      if self.is_current(timer) {
+        break;
+      }
+      self.timers.pop();
+    }
+  }
+  /// Rebuilds the heap from the live entries once stale ones outnumber them,
+  /// so that users rescheduled over and over do not grow it without bound.
+  fn compact(&mut self) {
+    if self.timers.len() <= 2 * self.live_len {
+      return;
+    }
+    let generations = &self.generations;
+    let timers: Vec<Reverse<Timer>> = self
+      .timers
+      .drain()
+      .filter(|Reverse(timer)| generations.get(&timer.user_id) == Some(&timer.generation))
+      .collect();
+    self.timers = BinaryHeap::from(timers);
+  }
+}
+
+/// Keeps the next due instant of every alarm in a priority queue, so that the
+/// cron thread only wakes up when something is about to ring.
+///
+/// Entries are addressed by `(user_id, kind)`. Whenever the alarms of a user
+/// change, the whole user is rescheduled under a new generation and the old
+/// entries are dropped lazily as they reach the top of the queue, or all at
+/// once when they make up most of it.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+  queue: Mutex<Queue>,
+  wakeup: Condvar,
+}
+
+impl Scheduler {
+  pub fn new() -> Scheduler {
+    Scheduler::default()
+  }
+  /// Marks the alarms of a user as changed and wakes the cron thread up to
+  /// reschedule them.
+  pub fn touch(&self, user_id: i64) {
+    self.queue.lock().unwrap().dirty.insert(user_id);
+    self.wakeup.notify_one();
+  }
+  pub fn take_dirty(&self) -> Vec<i64> {
+    self.queue.lock().unwrap().dirty.drain().collect()
+  }
+  /// Replaces every entry of a user with what is due for them in `state`
+  /// after `after`: enabled alarms as `get_due_timestamp` has them, and the
+  /// other kinds of `TimerKind`.
+  pub fn reschedule(&self, state: &State, user_id: i64, after: i64) {
+    let dues: Vec<(TimerKind, i64)> = {
+      let alarms_map = state.alarms.borrow();
+      let timezone_map = state.timezone.borrow();
+      let bedtime_map = state.bedtime.borrow();
+      let vacation_map = state.vacation.borrow();
+      let vacation = vacation_map.get(&user_id);
+      let quiet_hours_map = state.quiet_hours.borrow();
+      let quiet_hours = quiet_hours_map.get(&user_id);
+      let tz = timezone_map
+        .get(&user_id)
+        .map(|tz| tz.parse::<Tz>().unwrap());
+      let mut dues = match alarms_map.get(&user_id) {
+        None => vec![],
+        Some(alarms) => {
+          let alarms = alarms.borrow();
+          let mut dues: Vec<(TimerKind, i64)> = alarms
+            .iter()
+            .filter(|alarm| !alarm.is_disabled)
+            .map(|alarm| match tz {
+              Some(tz) => (
+                TimerKind::Alarm(alarm.id),
+                get_due_timestamp(alarm, tz, after, vacation, quiet_hours),
+              ),
+              None => (
+                TimerKind::Alarm(alarm.id),
+                get_due_timestamp(alarm, chrono::Local, after, vacation, quiet_hours),
+              ),
+            })
+            .filter(|(_, due)| *due >= 0)
+            .collect();
+          for alarm in alarms.iter() {
+            let occurrence = match tz {
+              Some(tz) => get_heads_up_occurrence(alarm, tz, after, vacation, quiet_hours),
+              None => get_heads_up_occurrence(alarm, chrono::Local, after, vacation, quiet_hours),
+            };
+            if occurrence >= 0 {
+              dues.push((TimerKind::HeadsUp(alarm.id), occurrence - alarm.heads_up));
+            }
+          }
+          if let Some(settings) = bedtime_map.get(&user_id) {
+            let reminder = match tz {
+              Some(tz) => get_bedtime(&alarms, settings, vacation, &tz.timestamp(after, 0))
+                .map(|bedtime| (bedtime.reminder.timestamp(), bedtime.wake.timestamp())),
+              None => get_bedtime(
+                &alarms,
+                settings,
+                vacation,
+                &chrono::Local.timestamp(after, 0),
+              )
+              .map(|bedtime| (bedtime.reminder.timestamp(), bedtime.wake.timestamp())),
+            };
+            if let Some((reminder, wake)) = reminder {
+              if reminder > after && wake != settings.reminded_wake {
+                dues.push((TimerKind::Bedtime, reminder));
+              }
+            }
+          }
+          dues
+        }
+      };
+      if let Some(vacation) = vacation {
+        dues.push((TimerKind::Vacation, vacation.until));
+      }
+      if let Some(countdowns) = state.countdowns.borrow().get(&user_id) {
+        for countdown in countdowns.borrow().iter() {
+          dues.push((TimerKind::Countdown(countdown.id), countdown.ends_at));
+        }
+      }
+      if let Some(user_sleeping) = state.sleeping.borrow().get(&user_id) {
+        for sleep in user_sleeping.borrow().iter() {
+          if let Some(until) = sleep.until {
+            dues.push((TimerKind::Sleep(sleep.chat_id), until));
+          }
+        }
+      }
+      if let Some(shared_alarms) = state.shared_alarms.borrow().get(&user_id) {
+        for shared in shared_alarms.borrow().iter() {
+          if let Some(due) = shared.summary_due() {
+            dues.push((TimerKind::SharedSummary(shared.id), due));
+          }
+        }
+      }
+      dues
+    };
+    let mut queue = self.queue.lock().unwrap();
+    let generation = {
+      let generation = queue.generations.entry(user_id).or_insert(0);
+      *generation += 1;
+      *generation
+    };
+    let stale = queue.live.insert(user_id, dues.len()).unwrap_or(0);
+    queue.live_len = queue.live_len - stale + dues.len();
+    for (kind, due) in dues {
+      queue.timers.push(Reverse(Timer {
+        due,
+        user_id,
+        kind,
+        generation,
+      }));
+    }
+    queue.compact();
+  }
+  /// Removes and returns every live entry due at or before `until`.
+  pub fn pop_due(&self, until: i64) -> Vec<Timer> {
+    let mut queue = self.queue.lock().unwrap();
+    let mut due = vec![];
+    while let Some(Reverse(timer)) = queue.timers.peek() {
+      if timer.due > until {
+        break;
+      }
+      let timer = *timer;
+      queue.timers.pop();
+      if queue.is_current(&timer) {
+        if let Some(live) = queue.live.get_mut(&timer.user_id) {
+          *live -= 1;
+          queue.live_len -= 1;
+        }
+        due.push(timer);
+      }
+    }
+    due
+  }
+  pub fn next_due(&self) -> Option<i64> {
+    let mut queue = self.queue.lock().unwrap();
+    queue.drop_stale();
+    queue.timers.peek().map(|Reverse(timer)| timer.due)
+  }
+  /// Number of live entries.
+  pub fn len(&self) -> usize {
+    self.queue.lock().unwrap().live_len
+  }
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+  /// Blocks until the earliest entry is due, some user is touched, or
+  /// `max_wait` has passed, whichever comes first.
+  pub fn wait(&self, clock: &dyn Clock, max_wait: Duration) {
+    let mut queue = self.queue.lock().unwrap();
+    if !queue.dirty.is_empty() {
+      return;
+    }
+    queue.drop_stale();
+    let timeout = match queue.timers.peek() {
+      None => max_wait,
+      Some(Reverse(timer)) => {
+        let millis = timer.due * 1000 - clock.now().timestamp_millis();
+        if millis <= 0 {
+          return;
+        }
+        Duration::from_millis(millis as u64).min(max_wait)
+      }
+    };
+    let _ = self.wakeup.wait_timeout(queue, timeout).unwrap();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::Alarm;
+  use chrono::Utc;
+  use std::cell::RefCell;
+
+  fn at(hour: u32) -> i64 {
+    Utc.ymd(2020, 1, 1).and_hms(hour, 0, 0).timestamp()
+  }
+
+  /// A state in which each user has one daily alarm at the given hour, UTC.
+  fn state_with(alarms: &[(i64, u32)]) -> State {
+    let state = State::new();
+    for (user_id, hour) in alarms {
+      set_alarm(&state, *user_id, *hour);
+    }
+    state
+  }
+
+  fn set_alarm(state: &State, user_id: i64, hour: u32) {
+    let cron = format!("0 0 {} * * *", hour);
+    let mut alarms = vec![];
+    state.add_alarm(
+      &mut alarms,
+      Alarm::new(user_id, user_id, cron.as_str(), "", false),
+    );
+    state
+      .alarms
+      .borrow_mut()
+      .insert(user_id, RefCell::new(alarms));
+    state
+      .timezone
+      .borrow_mut()
+      .insert(user_id, String::from("UTC"));
+  }
+
+  #[test]
+  fn reschedule_replaces_the_old_entries() {
+    let state = state_with(&[(1, 7)]);
+    let scheduler = Scheduler::new();
+    scheduler.reschedule(&state, 1, at(0));
+    set_alarm(&state, 1, 8);
+    scheduler.reschedule(&state, 1, at(0));
+    assert_eq!(scheduler.len(), 1);
+    assert_eq!(scheduler.next_due(), Some(at(8)));
+    let timers = scheduler.pop_due(at(12));
+    assert_eq!(timers.len(), 1);
+    assert_eq!(timers[0].due, at(8));
+    assert!(scheduler.is_empty());
+    assert_eq!(scheduler.next_due(), None);
+  }
+
+  #[test]
+  fn pop_due_in_order_up_to_until() {
+    let state = state_with(&[(1, 7), (2, 5), (3, 9)]);
+    let scheduler = Scheduler::new();
+    for user_id in 1..=3 {
+      scheduler.reschedule(&state, user_id, at(0));
+    }
+    let timers = scheduler.pop_due(at(8));
+    let dues: Vec<(i64, i64)> = timers
+      .iter()
+      .map(|timer| (timer.user_id, timer.due))
+      .collect();
+    assert_eq!(dues, vec![(2, at(5)), (1, at(7))]);
+    assert_eq!(scheduler.len(), 1);
+    assert_eq!(scheduler.next_due(), Some(at(9)));
+  }
+
+  #[test]
+  fn rescheduling_over_and_over_compacts_the_queue() {
+    let state = state_with(&[(1, 7), (2, 8)]);
+    let scheduler = Scheduler::new();
+    scheduler.reschedule(&state, 2, at(0));
+    for _ in 0..100 {
+      scheduler.reschedule(&state, 1, at(0));
+    }
+    assert_eq!(scheduler.len(), 2);
+    assert!(scheduler.queue.lock().unwrap().timers.len() <= 4);
+    let timers = scheduler.pop_due(at(12));
+    assert_eq!(timers.len(), 2);
+  }
+}
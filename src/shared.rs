@@ -0,0 +1,83 @@
+use crate::store::{Alarm, SharedAlarm, WakeOutcome};
+use std::collections::HashMap;
+
+/// Seconds after the first member's alarm rang that the group is told who
+/// responded, unless everyone did earlier.
+pub const SUMMARY_AFTER: i64 = 1800;
+
+impl SharedAlarm {
+  pub fn new<T>(id: i64, cron: T, title: T) -> SharedAlarm
+  where
+    T: AsRef<str>,
+  {
+    SharedAlarm {
+      id,
+      cron: String::from(cron.as_ref()),
+      title: String::from(title.as_ref()),
+      subscribers: vec![],
+      round_started: None,
+      responses: HashMap::new(),
+    }
+  }
+  /// The alarm `user_id` gets on joining, ringing in `chat_id`.
+  pub fn subscribe(&mut self, user_id: i64, chat_id: i64) -> Option<Alarm> {
+    if self.subscribers.contains(&user_id) {
+      return None;
+    }
+    self.subscribers.push(user_id);
+    let mut alarm = Alarm::new(user_id, chat_id, &self.cron, &self.title, false);
+    alarm.shared_id = Some(self.id);
+    Some(alarm)
+  }
+  pub fn unsubscribe(&mut self, user_id: i64) -> bool {
+    let len = self.subscribers.len();
+    self.subscribers.retain(|id| *id != user_id);
+    self.responses.remove(&user_id);
+    self.subscribers.len() < len
+  }
+  /// Starts an occurrence when a member's alarm rings, unless one is going on.
+  pub fn start_round(&mut self, now: i64) -> bool {
+    if self.round_started.is_some() {
+      return false;
+    }
+    self.round_started = Some(now);
+    self.responses.clear();
+    true
+  }
+  /// Records how the alarm of `user_id` ended. Returns whether every member
+  /// has now responded.
+  pub fn respond(&mut self, user_id: i64, outcome: WakeOutcome) -> bool {
+    if self.round_started.is_none() || !self.subscribers.contains(&user_id) {
+      return false;
+    }
+    self.responses.insert(user_id, outcome);
+    self
+      .subscribers
+      .iter()
+      .all(|id| self.responses.contains_key(id))
+  }
+  pub fn summary_due(&self) -> Option<i64> {
+    self.round_started.map(|started| started + SUMMARY_AFTER)
+  }
+  /// Ends the current occurrence, returning how each member responded in
+  /// the order they joined, `None` for members who did not.
+  pub fn end_round(&mut self) -> Vec<(i64, Option<WakeOutcome>)> {
+    let responses = self
+      .subscribers
+      .iter()
+      .map(|user_id| (*user_id, self.responses.get(user_id).cloned()))
+      .collect();
+    self.round_started = None;
+    self.responses.clear();
+    responses
+  }
+}
+
+pub fn next_shared_id(shared_alarms: &[SharedAlarm]) -> i64 {
+  shared_alarms
+    .iter()
+    .map(|shared| shared.id)
+    .max()
+    .unwrap_or(0)
+    + 1
+}
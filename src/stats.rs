@@ -0,0 +1,118 @@
+use crate::ring::{Ring, RingEvent};
+use crate::store::{GroupStats, WakeOutcome, WakeRecord};
+
+/// Days of outcomes kept for the leaderboard and the weekly summary.
+pub const RECORD_DAYS: i64 = 30;
+pub const WEEK: i64 = 7 * 86400;
+
+/// A member of a group on the leaderboard.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Standing {
+  pub user_id: i64,
+  pub streak: u32,
+  pub best_streak: u32,
+  /// Outcomes since the start of the period, in the order of `WakeOutcome`.
+  pub outcomes: [u32; 4],
+  /// Mean seconds to fulfillment, `None` if never fulfilled.
+  pub average_time: Option<i64>,
+}
+
+impl WakeRecord {
+  /// The outcome of `ring`, which ended at `now`, fulfilled unless
+  /// `is_failed`.
+  pub fn new(user_id: i64, ring: &Ring, is_failed: bool, now: i64) -> WakeRecord {
+    let outcome = if is_failed {
+      WakeOutcome::Failed
+    } else if ring.is_helped {
+      WakeOutcome::Helped
+    } else if ring.attempts > 1 {
+      WakeOutcome::Retried
+    } else {
+      WakeOutcome::FirstCall
+    };
+    let first_fired = ring
+      .history
+      .iter()
+      .find_map(|record| match record.event {
+        RingEvent::Fired(_) => Some(record.at),
+        _ => None,
+      })
+      .unwrap_or(now);
+    WakeRecord {
+      user_id,
+      at: now,
+      outcome,
+      time_to_dismiss: match outcome {
+        WakeOutcome::Failed => 0,
+        _ => now - first_fired,
+      },
+    }
+  }
+}
+
+impl GroupStats {
+  /// Adds an outcome, moving the streak of its user on, and forgets outcomes
+  /// older than `RECORD_DAYS`.
+  pub fn record(&mut self, record: WakeRecord) {
+    let streak = self.streaks.entry(record.user_id).or_insert(0);
+    match record.outcome {
+      WakeOutcome::FirstCall | WakeOutcome::Retried => *streak += 1,
+      WakeOutcome::Helped | WakeOutcome::Failed => *streak = 0,
+    }
+    let best_streak = self.best_streaks.entry(record.user_id).or_insert(0);
+    *best_streak = (*best_streak).max(*streak);
+    let oldest = record.at - RECORD_DAYS * 86400;
+    self.records.retain(|record| record.at >= oldest);
+    self.records.push(record);
+  }
+  /// Forgets everything recorded about `user_id`.
+  pub fn forget(&mut self, user_id: i64) {
+    self.records.retain(|record| record.user_id != user_id);
+    self.streaks.remove(&user_id);
+    self.best_streaks.remove(&user_id);
+  }
+  /// Standings of every member with an outcome since `since`, best first:
+  /// longest streak, then fastest on average.
+  pub fn standings(&self, since: i64) -> Vec<Standing> {
+    let mut standings: Vec<Standing> = vec![];
+    let mut total_times: Vec<i64> = vec![];
+    for record in self.records.iter().filter(|record| record.at >= since) {
+      let index = match standings
+        .iter()
+        .position(|standing| standing.user_id == record.user_id)
+      {
+        Some(index) => index,
+        None => {
+          standings.push(Standing {
+            user_id: record.user_id,
+            streak: self.streaks.get(&record.user_id).cloned().unwrap_or(0),
+            best_streak: self.best_streaks.get(&record.user_id).cloned().unwrap_or(0),
+            outcomes: [0; 4],
+            average_time: None,
+          });
+          total_times.push(0);
+          standings.len() - 1
+        }
+      };
+      standings[index].outcomes[record.outcome as usize] += 1;
+      total_times[index] += record.time_to_dismiss;
+    }
+    for (standing, total_time) in standings.iter_mut().zip(total_times) {
+      let fulfilled = standing.outcomes[..3].iter().sum::<u32>() as i64;
+      if fulfilled > 0 {
+        standing.average_time = Some(total_time / fulfilled);
+      }
+    }
+    standings.sort_by_key(|standing| {
+      (
+        std::cmp::Reverse(standing.streak),
+        standing.average_time.unwrap_or(std::i64::MAX),
+      )
+    });
+    standings
+  }
+  /// Whether the weekly summary is due at `now`.
+  pub fn is_summary_due(&self, now: i64) -> bool {
+    self.is_weekly && now - self.summary_sent >= WEEK
+  }
+}
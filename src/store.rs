@@ -1,13 +1,42 @@
+use crate::challenge::{Challenge, ChallengeKind, Difficulty};
+use crate::cmd::parse_tags;
+use crate::render::RenderSettings;
+use crate::ring::{Fallback, FollowUp, Ring, RingState};
+use chrono::{DateTime, Duration, TimeZone, Timelike};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fmt::{Display, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 use std::fs;
-use std::sync::{Mutex, MutexGuard};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A cron expression parsed once, when its alarm is created or loaded.
+#[derive(Clone)]
+pub struct CronSchedule(Arc<cron::Schedule>);
+
+impl Debug for CronSchedule {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+    f.write_str("CronSchedule")
+  }
+}
+
+impl Deref for CronSchedule {
+  type Target = cron::Schedule;
+  fn deref(&self) -> &cron::Schedule {
+    &self.0
+  }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Alarm {
+  /// Unique among all alarms and never reused, so that timers can point at
+  /// an alarm whatever happens to the list it is in. Given by
+  /// `State::add_alarm`, or when loading alarms saved before IDs existed.
+  #[serde(default)]
+  pub id: i64,
   pub user_id: i64,
   pub chat_id: i64,
   pub cron: String,
@@ -15,10 +44,52 @@ pub struct Alarm {
   pub is_strict: bool,
   pub is_onceoff: bool,
   pub is_disabled: bool,
-  pub is_pending: bool,
-  pub is_informing: i64,
-  pub strict_challenge: String,
-  pub reschedule: i64,
+  #[serde(default)]
+  pub ring: RingState,
+  /// Ring progress of alarms saved before `ring` existed. Only read when
+  /// loading, see `Alarm::migrate_ring`.
+  #[serde(default, rename = "is_informing", skip_serializing)]
+  legacy_informing: i64,
+  #[serde(default, rename = "reschedule", skip_serializing)]
+  legacy_reschedule: i64,
+  #[serde(default, rename = "strict_challenge", skip_serializing)]
+  legacy_challenge: String,
+  #[serde(default)]
+  pub is_bedtime_off: bool,
+  /// The challenge a strict alarm asks for, see `ChallengeType`.
+  #[serde(default)]
+  pub challenge_kind: ChallengeKind,
+  #[serde(default)]
+  pub difficulty: Difficulty,
+  /// Wrong answers after which a new challenge is issued, 0 for never.
+  #[serde(default)]
+  pub regenerate_after: u32,
+  /// How the challenge prompt is drawn as an image, sent as text if `None`.
+  #[serde(default)]
+  pub render: Option<RenderSettings>,
+  /// Seconds an answered call must stay connected, 0 for none.
+  #[serde(default)]
+  pub min_call_duration: i64,
+  /// Channels to try when calls fail, calls only if `None`.
+  #[serde(default)]
+  pub fallback: Option<Fallback>,
+  #[serde(default)]
+  pub follow_up: Option<FollowUp>,
+  /// Seconds before each occurrence to send a heads-up, 0 for none.
+  #[serde(default)]
+  pub heads_up: i64,
+  /// The occurrence the last heads-up was sent for.
+  #[serde(default)]
+  pub heads_up_sent: i64,
+  /// Tags without the leading `#`. Only `None` for alarms saved before tags
+  /// existed, which get tagged from their title when loaded.
+  #[serde(default)]
+  pub tags: Option<Vec<String>>,
+  /// The `SharedAlarm` of `chat_id` this alarm was joined from.
+  #[serde(default)]
+  pub shared_id: Option<i64>,
+  #[serde(skip)]
+  pub schedule: Option<CronSchedule>,
 }
 
 impl Alarm {
@@ -26,7 +97,8 @@ impl Alarm {
   where
     T: AsRef<str>,
   {
-    Alarm {
+    let mut alarm = Alarm {
+      id: 0,
       user_id,
       chat_id,
       cron: String::from(cron.as_ref()),
@@ -34,11 +106,64 @@ impl Alarm {
       is_strict,
       is_onceoff: false,
       is_disabled: false,
-      is_pending: false,
-      is_informing: 0,
-      strict_challenge: String::default(),
-      reschedule: 0,
+      ring: RingState::Idle,
+      legacy_informing: 0,
+      legacy_reschedule: 0,
+      legacy_challenge: String::default(),
+      is_bedtime_off: false,
+      challenge_kind: ChallengeKind::default(),
+      difficulty: Difficulty::default(),
+      regenerate_after: 0,
+      render: None,
+      min_call_duration: 0,
+      fallback: None,
+      follow_up: None,
+      heads_up: 0,
+      heads_up_sent: 0,
+      tags: Some(parse_tags(title.as_ref())),
+      shared_id: None,
+      schedule: None,
+    };
+    alarm.parse_schedule();
+    alarm
+  }
+  /// Parses `cron` into the cached schedule. An alarm whose expression does
+  /// not parse is kept but quarantined: it never rings and is listed as broken.
+  pub fn parse_schedule(&mut self) -> bool {
+    self.schedule = cron::Schedule::from_str(self.cron.as_str())
+      .ok()
+      .map(|schedule| CronSchedule(Arc::new(schedule)));
+    self.schedule.is_some()
+  }
+  /// Turns the `is_informing` counter of an old save into a `RingState`. A
+  /// call cannot outlive a restart, so a ringing alarm resumes waiting. The
+  /// counter went up by 2 once the group was asked for help.
+  pub fn migrate_ring(&mut self) {
+    if self.legacy_informing == 0 {
+      return;
     }
+    self.ring = RingState::Waiting(Ring {
+      retry_at: self.legacy_reschedule,
+      is_helped: self.legacy_informing >= 3,
+      challenge: match self.legacy_challenge.as_str() {
+        "" => None,
+        challenge => Some(Challenge::legacy(challenge)),
+      },
+      ..Ring::default()
+    });
+    self.legacy_informing = 0;
+  }
+  pub fn is_broken(&self) -> bool {
+    self.schedule.is_none()
+  }
+  pub fn tags(&self) -> &[String] {
+    match &self.tags {
+      None => &[],
+      Some(tags) => tags,
+    }
+  }
+  pub fn has_tag(&self, tag: &str) -> bool {
+    self.tags().iter().any(|t| t == tag)
   }
 }
 
@@ -51,23 +176,308 @@ impl Display for Alarm {
   }
 }
 
+/// How long a user wants to sleep before their next wake alarm, and how long
+/// before bedtime they want to be reminded. Both are in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bedtime {
+  pub sleep_duration: i64,
+  pub wind_down: i64,
+  /// Timestamp of the wake alarm the last reminder was sent for.
+  #[serde(default)]
+  pub reminded_wake: i64,
+}
+
+impl Bedtime {
+  pub fn new(sleep_duration: i64, wind_down: i64) -> Bedtime {
+    Bedtime {
+      sleep_duration,
+      wind_down,
+      reminded_wake: 0,
+    }
+  }
+}
+
+/// Suspends the alarms of a user until the timestamp `until`, either all of
+/// them or only those tagged with `tag`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vacation {
+  pub until: i64,
+  pub tag: Option<String>,
+}
+
+impl Vacation {
+  pub fn suspends(&self, alarm: &Alarm) -> bool {
+    match &self.tag {
+      None => true,
+      Some(tag) => alarm.has_tag(tag),
+    }
+  }
+}
+
+/// Daily window in local time, from `start` up to `end` minutes after
+/// midnight, in which non-strict alarms are deferred to the end of the window,
+/// or dropped when `is_dropping` is set. The window wraps past midnight when
+/// `end` is before `start`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuietHours {
+  pub start: u32,
+  pub end: u32,
+  pub is_dropping: bool,
+}
+
+impl QuietHours {
+  pub fn contains<Z>(&self, at: &DateTime<Z>) -> bool
+  where
+    Z: TimeZone,
+  {
+    let minute = at.hour() * 60 + at.minute();
+    if self.start <= self.end {
+      minute >= self.start && minute < self.end
+    } else {
+      minute >= self.start || minute < self.end
+    }
+  }
+  /// Returns the first end of the window after `at`.
+  pub fn end_after<Z>(&self, at: &DateTime<Z>) -> DateTime<Z>
+  where
+    Z: TimeZone,
+  {
+    let mut date = at.naive_local().date();
+    if at.hour() * 60 + at.minute() >= self.end {
+      date = date.succ();
+    }
+    let end = date.and_hms(self.end / 60, self.end % 60, 0);
+    let timezone = at.timezone();
+    match timezone.from_local_datetime(&end).earliest() {
+      Some(end) => end,
+      // The end falls into a gap of a DST change.
+      None => timezone
+        .from_local_datetime(&(end + Duration::hours(1)))
+        .earliest()
+        .unwrap_or_else(|| at.clone() + Duration::hours(1)),
+    }
+  }
+}
+
+/// How a ring of a group alarm ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WakeOutcome {
+  /// Fulfilled on the first call.
+  FirstCall,
+  /// Fulfilled after more than one call.
+  Retried,
+  /// Fulfilled after the group was asked for help.
+  Helped,
+  /// Stopped without being fulfilled.
+  Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WakeRecord {
+  pub user_id: i64,
+  pub at: i64,
+  pub outcome: WakeOutcome,
+  /// Seconds from the first ring to fulfillment, 0 if failed.
+  pub time_to_dismiss: i64,
+}
+
+/// Wake-up statistics of the members of a group, see `#leaderboard`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GroupStats {
+  /// Outcomes of the last `RECORD_DAYS` days, oldest first.
+  pub records: Vec<WakeRecord>,
+  /// Wake-ups in a row without help or failure, per user.
+  pub streaks: HashMap<i64, u32>,
+  pub best_streaks: HashMap<i64, u32>,
+  /// Whether the group gets a weekly summary.
+  pub is_weekly: bool,
+  /// When the last weekly summary was sent, or the group opted in.
+  pub summary_sent: i64,
+}
+
+/// Where the user sleeps, for the location challenge.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Home {
+  pub latitude: f64,
+  pub longitude: f64,
+  /// Meters away from home a shared location must be to solve the challenge.
+  pub distance: f64,
+}
+
+impl Home {
+  /// Great-circle distance in meters from home to a location.
+  pub fn distance_to(&self, latitude: f64, longitude: f64) -> f64 {
+    const EARTH_RADIUS: f64 = 6_371_000.0;
+    let (lat1, lat2) = (self.latitude.to_radians(), latitude.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (longitude - self.longitude).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+  }
+}
+
+/// Someone to alert when the user does not wake up, after `after` attempts
+/// of an alarm went unanswered. `contact_id` is 0 until the person the user
+/// named opted in with `#buddy`. A user has at most one contact.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmergencyContact {
+  pub contact_id: i64,
+  pub after: i64,
+  pub is_calling: bool,
+  /// Who the user named, a user ID or a lowercase username without `@`.
+  /// Only they can opt in.
+  #[serde(default)]
+  pub invitee: String,
+}
+
+impl EmergencyContact {
+  /// Whether the user with this ID and username is the one who was named.
+  pub fn is_invitee(&self, user_id: i64, username: &str) -> bool {
+    !self.invitee.is_empty()
+      && (self.invitee == user_id.to_string() || self.invitee == username.to_lowercase())
+  }
+}
+
+/// What an incoming call from the user does. Users who never chose get
+/// `Snooze`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IncomingCall {
+  /// Hang up and do nothing else.
+  Ignore,
+  /// Snooze the ringing alarms, or reply with the next alarm when nothing is
+  /// ringing.
+  Snooze,
+  /// Like `Snooze`, but dismiss the ringing alarms that are not strict.
+  Dismiss,
+}
+
+/// An alarm owned by a group, set with `#shared`. Members who `#join` it get
+/// an alarm of their own pointing back here through `shared_id`, which rings
+/// and is fulfilled like any other. `id` is unique among the shared alarms of
+/// its group.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedAlarm {
+  pub id: i64,
+  pub cron: String,
+  pub title: String,
+  pub subscribers: Vec<i64>,
+  /// When the first member's alarm rang for the current occurrence, `None`
+  /// between occurrences.
+  pub round_started: Option<i64>,
+  /// How the members' alarms ended in the current occurrence.
+  pub responses: HashMap<i64, WakeOutcome>,
+}
+
+/// A group where the user muted themselves with `#sleep!`. The mute is lifted
+/// when one of their alarms is fulfilled, or at `until` if it is timed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "StoredSleep")]
+pub struct Sleep {
+  pub chat_id: i64,
+  pub until: Option<i64>,
+}
+
+/// A `Sleep` as saved now, or the bare chat ID saves kept before `#sleep!`
+/// could be timed.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredSleep {
+  Legacy(i64),
+  Current { chat_id: i64, until: Option<i64> },
+}
+
+impl From<StoredSleep> for Sleep {
+  fn from(stored: StoredSleep) -> Sleep {
+    match stored {
+      StoredSleep::Legacy(chat_id) => Sleep {
+        chat_id,
+        until: None,
+      },
+      StoredSleep::Current { chat_id, until } => Sleep { chat_id, until },
+    }
+  }
+}
+
+/// A countdown started with `#timer` or `#pomodoro`, tracked apart from the
+/// cron alarms. `id` is unique among the running countdowns of its user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Countdown {
+  pub id: i64,
+  pub chat_id: i64,
+  pub title: String,
+  pub ends_at: i64,
+  pub is_calling: bool,
+  pub pomodoro: Option<Pomodoro>,
+}
+
+/// Progress of a pomodoro session. `round` counts from 1 and durations are in
+/// seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pomodoro {
+  pub work: i64,
+  pub rest: i64,
+  pub rounds: i64,
+  pub round: i64,
+  pub is_resting: bool,
+  pub focused: i64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct State {
   pub alarms: RefCell<HashMap<i64, RefCell<Vec<Alarm>>>>,
+  /// The ID given to the last alarm added, see `Alarm::id`.
+  #[serde(default)]
+  pub last_alarm_id: RefCell<i64>,
   pub timezone: RefCell<HashMap<i64, String>>,
-  pub sleeping: RefCell<HashMap<i64, RefCell<Vec<i64>>>>,
+  pub sleeping: RefCell<HashMap<i64, RefCell<Vec<Sleep>>>>,
   pub users: RefCell<HashMap<i64, String>>,
+  #[serde(default)]
+  pub bedtime: RefCell<HashMap<i64, Bedtime>>,
+  #[serde(default)]
+  pub vacation: RefCell<HashMap<i64, Vacation>>,
+  #[serde(default)]
+  pub quiet_hours: RefCell<HashMap<i64, QuietHours>>,
+  #[serde(default)]
+  pub contacts: RefCell<HashMap<i64, EmergencyContact>>,
+  #[serde(default)]
+  pub incoming_call: RefCell<HashMap<i64, IncomingCall>>,
+  #[serde(default)]
+  pub homes: RefCell<HashMap<i64, Home>>,
+  #[serde(default)]
+  pub group_stats: RefCell<HashMap<i64, GroupStats>>,
+  #[serde(default)]
+  pub countdowns: RefCell<HashMap<i64, RefCell<Vec<Countdown>>>>,
+  #[serde(default)]
+  pub shared_alarms: RefCell<HashMap<i64, RefCell<Vec<SharedAlarm>>>>,
 }
 
 impl State {
   pub fn new() -> State {
     State {
       alarms: RefCell::new(HashMap::new()),
+      last_alarm_id: RefCell::new(0),
       timezone: RefCell::new(HashMap::new()),
       users: RefCell::new(HashMap::new()),
       sleeping: RefCell::new(HashMap::new()),
+      bedtime: RefCell::new(HashMap::new()),
+      vacation: RefCell::new(HashMap::new()),
+      quiet_hours: RefCell::new(HashMap::new()),
+      contacts: RefCell::new(HashMap::new()),
+      incoming_call: RefCell::new(HashMap::new()),
+      homes: RefCell::new(HashMap::new()),
+      group_stats: RefCell::new(HashMap::new()),
+      countdowns: RefCell::new(HashMap::new()),
+      shared_alarms: RefCell::new(HashMap::new()),
     }
   }
+  /// Gives `alarm` the next ID and adds it to `alarms`. Returns the ID.
+  pub fn add_alarm(&self, alarms: &mut Vec<Alarm>, mut alarm: Alarm) -> i64 {
+    let mut last_alarm_id = self.last_alarm_id.borrow_mut();
+    *last_alarm_id += 1;
+    alarm.id = *last_alarm_id;
+    alarms.push(alarm);
+    *last_alarm_id
+  }
 }
 
 pub struct Store {
@@ -89,10 +499,31 @@ impl Store {
     T: AsRef<str>,
   {
     let contents = fs::read_to_string(path.as_ref());
-    let state = match contents {
+    let state: State = match contents {
       Err(_) => State::new(),
       Ok(string) => serde_json::from_str(string.as_str()).expect("Bad JSON"),
     };
+    for user_alarms in state.alarms.borrow().values() {
+      for alarm in user_alarms.borrow().iter() {
+        state
+          .last_alarm_id
+          .replace_with(|last| (*last).max(alarm.id));
+      }
+    }
+    for user_alarms in state.alarms.borrow().values() {
+      for alarm in user_alarms.borrow_mut().iter_mut() {
+        if alarm.id == 0 {
+          alarm.id = state.last_alarm_id.replace_with(|last| *last + 1) + 1;
+        }
+        if alarm.tags.is_none() {
+          alarm.tags = Some(parse_tags(&alarm.title));
+        }
+        alarm.migrate_ring();
+        if !alarm.parse_schedule() {
+          println!("Quarantined alarm {} due to bad cron expression", alarm);
+        }
+      }
+    }
     let store = Store {
       path: String::from(path.as_ref()),
       state: Mutex::new(state.clone()),